@@ -0,0 +1,230 @@
+use std::fmt::Display;
+
+use halo2_proofs::plonk::ConstraintSystem;
+use itertools::Itertools;
+
+use crate::analysis::{guards, queries, ColumnKind, Guard, Query};
+use crate::extraction::ExtractingAssignment;
+use crate::field::TermField;
+
+// Why a queried row is suspicious
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BoundaryIssue {
+    // The row is one of the blinding rows, or the last row, which hold random values in the real prover
+    Blinding,
+    // The row is at or past n, so the query wraps around to the start of the table
+    WrapsAround,
+    // The row is negative, so the query wraps around to the end of the table
+    Negative,
+}
+
+impl Display for BoundaryIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BoundaryIssue::Blinding => write!(f, "a blinding row"),
+            BoundaryIssue::WrapsAround => write!(f, "past the end of the table"),
+            BoundaryIssue::Negative => write!(f, "before the start of the table"),
+        }
+    }
+}
+
+// A gate enabled at some row whose rotated queries leave the usable region
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BoundaryViolation {
+    pub gate_name: String,
+    pub constraint_name: String,
+    // Every selector guarding the polynomial, empty if it is unguarded or guarded by fixed columns only
+    pub selectors: Vec<usize>,
+    pub region: Option<String>,
+    pub row: usize,
+    // (query, the row it lands on, why that row is suspicious)
+    pub queries: Vec<(Query, i64, BoundaryIssue)>,
+}
+
+impl Display for BoundaryViolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "gate \"{}\" ({})", self.gate_name, self.constraint_name)?;
+        if !self.selectors.is_empty() {
+            write!(f, " selectors {}", self.selectors.iter().join(", "))?;
+        }
+        if let Some(region) = &self.region {
+            write!(f, " region \"{region}\"")?;
+        }
+        let queries = self
+            .queries
+            .iter()
+            .map(|(query, queried_row, issue)| {
+                let kind = match query.kind {
+                    ColumnKind::Advice => "advice",
+                    ColumnKind::Fixed => "fixed",
+                    ColumnKind::Instance => "instance",
+                };
+                format!("{kind} {} rotation {} is row {queried_row}, {issue}", query.column, query.rotation)
+            })
+            .join("; ");
+        write!(f, " at row {}: {queries}", self.row)
+    }
+}
+
+impl ExtractingAssignment<TermField> {
+    // Every enabled gate instance that queries a blinding row, wraps around n or queries a negative row, for a table of 2^k rows
+    // A guarded gate is checked at the rows its guards enable, an unguarded one at every usable row of the table,
+    // as halo2 enforces it there whether or not the circuit assigned the row
+    pub fn rotation_boundary_violations(&self, cs: &ConstraintSystem<TermField>, k: u32) -> Vec<BoundaryViolation> {
+        let n = 1i64 << k;
+        let usable_rows = n - (cs.blinding_factors() as i64 + 1);
+        let mut res = vec![];
+
+        for gate in cs.gates() {
+            for (poly_idx, polynomial) in gate.polynomials().iter().enumerate() {
+                let poly_guards = guards(polynomial);
+                let selectors = poly_guards
+                    .iter()
+                    .filter_map(|guard| match guard {
+                        Guard::Selector(selector) => Some(*selector),
+                        Guard::Fixed(_, _) => None,
+                    })
+                    .sorted()
+                    .dedup()
+                    .collect_vec();
+                let poly_queries = queries(polynomial);
+                let rows = if poly_guards.is_empty() {
+                    (0..usable_rows.max(0) as usize).collect_vec()
+                } else {
+                    self.active_rows(polynomial)
+                };
+
+                for row in rows {
+                    let bad_queries = poly_queries
+                        .iter()
+                        .filter_map(|query| {
+                            let queried_row = row as i64 + query.rotation as i64;
+                            let issue = if queried_row < 0 {
+                                BoundaryIssue::Negative
+                            } else if queried_row >= n {
+                                BoundaryIssue::WrapsAround
+                            } else if queried_row >= usable_rows {
+                                BoundaryIssue::Blinding
+                            } else {
+                                return None;
+                            };
+                            Some((*query, queried_row, issue))
+                        })
+                        .collect_vec();
+                    if !bad_queries.is_empty() {
+                        res.push(BoundaryViolation {
+                            gate_name: gate.name().to_string(),
+                            constraint_name: gate.constraint_name(poly_idx).to_string(),
+                            selectors: selectors.clone(),
+                            region: self.region_at(row).map(str::to_string),
+                            row,
+                            queries: bad_queries,
+                        });
+                    }
+                }
+            }
+        }
+
+        res
+    }
+
+    pub fn print_rotation_boundary_violations(&self, cs: &ConstraintSystem<TermField>, k: u32) {
+        let violations = self.rotation_boundary_violations(cs, k);
+        println!("-- Gates querying rows outside the usable region for k = {k}:");
+        if violations.is_empty() {
+            println!("--   None");
+        }
+        for violation in violations {
+            println!("--WARNING: {violation}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use halo2_proofs::circuit::{Layouter, SimpleFloorPlanner, Value};
+    use halo2_proofs::plonk::{Advice, Circuit, Column, Selector};
+    use halo2_proofs::poly::Rotation;
+
+    use super::*;
+    use crate::extraction::ExtractionOptions;
+
+    const K: u32 = 4;
+
+    // s0 * s1 * (a[next] - a) enabled at row, and the unguarded b[prev] = 0
+    struct BoundaryCircuit {
+        row: usize,
+    }
+
+    impl Circuit<TermField> for BoundaryCircuit {
+        type Config = (Column<Advice>, Column<Advice>, Selector, Selector);
+        type FloorPlanner = SimpleFloorPlanner;
+        type Params = ();
+
+        fn without_witnesses(&self) -> Self {
+            Self { row: self.row }
+        }
+
+        fn configure(meta: &mut ConstraintSystem<TermField>) -> Self::Config {
+            let (a, b) = (meta.advice_column(), meta.advice_column());
+            let (s0, s1) = (meta.complex_selector(), meta.complex_selector());
+            meta.create_gate("next", |meta| {
+                let guard = meta.query_selector(s0) * meta.query_selector(s1);
+                vec![guard * (meta.query_advice(a, Rotation::next()) - meta.query_advice(a, Rotation::cur()))]
+            });
+            meta.create_gate("prev", |meta| vec![meta.query_advice(b, Rotation::prev())]);
+            (a, b, s0, s1)
+        }
+
+        fn synthesize(&self, (a, b, s0, s1): Self::Config, mut layouter: impl Layouter<TermField>) -> Result<(), halo2_frontend::plonk::Error> {
+            layouter.assign_region(
+                || "boundary",
+                |mut region| {
+                    region.assign_advice(|| "b", b, 0, || Value::known(TermField::zero()))?;
+                    region.assign_advice(|| "a", a, self.row, || Value::known(TermField::zero()))?;
+                    s0.enable(&mut region, self.row)?;
+                    s1.enable(&mut region, self.row)?;
+                    Ok(())
+                },
+            )
+        }
+    }
+
+    #[test]
+    fn test_rotation_boundary_violations() {
+        let mut cs = ConstraintSystem::default();
+        BoundaryCircuit::configure(&mut cs);
+        let last = (1 << K) - (cs.blinding_factors() + 1) - 1;
+
+        let (cs, extraction) = ExtractingAssignment::synthesize_circuit(&BoundaryCircuit { row: last }, &ExtractionOptions::default()).unwrap();
+        let violations = extraction.rotation_boundary_violations(&cs, K);
+        let advice = |column, rotation| Query { kind: ColumnKind::Advice, column, rotation };
+        assert_eq!(
+            violations,
+            vec![
+                // The gate is enabled at the last usable row, so a[next] is the first blinding row
+                BoundaryViolation {
+                    gate_name: "next".to_string(),
+                    constraint_name: "".to_string(),
+                    selectors: vec![0, 1],
+                    region: Some("boundary".to_string()),
+                    row: last,
+                    queries: vec![(advice(0, 1), last as i64 + 1, BoundaryIssue::Blinding)],
+                },
+                // The unguarded gate holds at row 0 too, where b[prev] wraps around
+                BoundaryViolation {
+                    gate_name: "prev".to_string(),
+                    constraint_name: "".to_string(),
+                    selectors: vec![],
+                    region: Some("boundary".to_string()),
+                    row: 0,
+                    queries: vec![(advice(1, -1), -1, BoundaryIssue::Negative)],
+                },
+            ]
+        );
+        assert_eq!(
+            violations[0].to_string(),
+            format!("gate \"next\" () selectors 0, 1 region \"boundary\" at row {last}: advice 0 rotation 1 is row {}, a blinding row", last + 1)
+        );
+    }
+}
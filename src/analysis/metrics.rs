@@ -0,0 +1,206 @@
+use std::fs;
+
+use halo2_proofs::plonk::{ConstraintSystem, Expression};
+use itertools::Itertools;
+
+use crate::analysis::{queries, ColumnKind, Query};
+use crate::extraction::{shuffle_name, ExtractingAssignment};
+use crate::field::TermField;
+use crate::utils::json_string;
+
+// Where the per-constraint metrics report is written, if anywhere
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum MetricsOutput {
+    #[default]
+    Off,
+    // A table on stderr, so it does not end up in the emitted Lean
+    Stderr,
+    // A JSON sidecar file at the given path
+    Json(String),
+}
+
+// Size measurements of one emitted gate polynomial, lookup or shuffle
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConstraintMetrics {
+    // The name of the emitted def, e.g. gate_3, lookup_0 or shuffle_foo
+    pub name: String,
+    pub description: String,
+    // For lookups and shuffles, the maximum over all input and table expressions
+    pub degree: usize,
+    // Number of nodes in the expression trees
+    pub nodes: usize,
+    pub queries: Vec<Query>,
+    // Length in characters of the simplified expressions as single line terms, with the column names of the model
+    // This is not the length of the emitted def, which also has the binder and, with CSE, the let bindings
+    pub term_len: usize,
+}
+
+impl ExtractingAssignment<TermField> {
+    pub fn constraint_metrics(&self, cs: &ConstraintSystem<TermField>) -> Vec<ConstraintMetrics> {
+        let mut res = vec![];
+
+        let gates = self.emitted_gates(cs);
+        for (idx, gate) in gates.iter().filter(|gate| gate.trivial.is_none()).enumerate() {
+            res.push(ConstraintMetrics {
+                name: format!("gate_{idx}"),
+                description: gate.description(),
+                degree: gate.polynomial.degree(),
+                nodes: node_count(&gate.polynomial),
+                queries: queries(&gate.polynomial),
+                term_len: self.expression_to_term(&gate.polynomial, "row").to_string().chars().count(),
+            });
+        }
+
+        for (idx, lookup) in cs.lookups().iter().enumerate() {
+            let exprs = lookup.input_expressions().iter().chain(lookup.table_expressions()).collect_vec();
+            res.push(self.tuple_metrics(format!("lookup_{idx}"), format!("Lookup number {} name: \"{}\"", idx+1, lookup.name()), &exprs));
+        }
+
+        for shuffle in cs.shuffles() {
+            let exprs = shuffle.input_expressions().iter().chain(shuffle.shuffle_expressions()).collect_vec();
+            res.push(self.tuple_metrics(shuffle_name(shuffle.name()), format!("Shuffle name: \"{}\"", shuffle.name()), &exprs));
+        }
+
+        res
+    }
+
+    pub fn report_constraint_metrics(&self, cs: &ConstraintSystem<TermField>, output: &MetricsOutput) {
+        // Only measured when reported, as simplifying every constraint is not free
+        match output {
+            MetricsOutput::Off => {},
+            MetricsOutput::Stderr => eprintln!("{}", format_metrics_table(&self.constraint_metrics(cs))),
+            MetricsOutput::Json(path) => fs::write(path, format_metrics_json(&self.constraint_metrics(cs))).expect("Failed to write metrics file"),
+        }
+    }
+
+    // Lookups and shuffles are measured after simplification too, as they are emitted that way
+    fn tuple_metrics(&self, name: String, description: String, exprs: &[&Expression<TermField>]) -> ConstraintMetrics {
+        let exprs = exprs.iter().map(|expr| self.prepare_expression(expr)).collect_vec();
+        let mut all_queries = exprs.iter().flat_map(queries).collect_vec();
+        all_queries.sort();
+        all_queries.dedup();
+        ConstraintMetrics {
+            name,
+            description,
+            degree: exprs.iter().map(|expr| expr.degree()).max().unwrap_or(0),
+            nodes: exprs.iter().map(node_count).sum(),
+            queries: all_queries,
+            term_len: exprs.iter().map(|expr| self.expression_to_term(expr, "row").to_string().chars().count()).sum(),
+        }
+    }
+}
+
+pub fn node_count(expr: &Expression<TermField>) -> usize {
+    match expr {
+        Expression::Constant(_)
+        | Expression::Selector(_)
+        | Expression::Fixed(_)
+        | Expression::Advice(_)
+        | Expression::Instance(_)
+        | Expression::Challenge(_) => 1,
+        Expression::Negated(inner) | Expression::Scaled(inner, _) => node_count(inner) + 1,
+        Expression::Sum(lhs, rhs) | Expression::Product(lhs, rhs) => node_count(lhs) + node_count(rhs) + 1,
+    }
+}
+
+// e.g. a0, a0@1, f2@-1
+fn format_query(query: &Query) -> String {
+    let kind = match query.kind {
+        ColumnKind::Advice => "a",
+        ColumnKind::Fixed => "f",
+        ColumnKind::Instance => "i",
+    };
+    if query.rotation == 0 {
+        format!("{kind}{}", query.column)
+    } else {
+        format!("{kind}{}@{}", query.column, query.rotation)
+    }
+}
+
+// Sorted by degree and then size, so the constraints responsible for a large circuit degree come first
+pub fn format_metrics_table(metrics: &[ConstraintMetrics]) -> String {
+    let sorted = metrics
+        .iter()
+        .sorted_by_key(|metric| (std::cmp::Reverse(metric.degree), std::cmp::Reverse(metric.nodes)))
+        .collect_vec();
+    let name_width = sorted.iter().map(|metric| metric.name.chars().count()).max().unwrap_or(0).max(4);
+    let mut lines = vec![format!("{:name_width$}  degree  nodes  length  queries", "name")];
+    for metric in sorted {
+        lines.push(format!(
+            "{:name_width$}  {:>6}  {:>5}  {:>6}  {}",
+            metric.name,
+            metric.degree,
+            metric.nodes,
+            metric.term_len,
+            metric.queries.iter().map(format_query).join(" ")
+        ));
+    }
+    lines.join("\n")
+}
+
+pub fn format_metrics_json(metrics: &[ConstraintMetrics]) -> String {
+    let entries = metrics
+        .iter()
+        .map(|metric| {
+            let queries = metric
+                .queries
+                .iter()
+                .map(|query| format!("{{\"kind\": \"{:?}\", \"column\": {}, \"rotation\": {}}}", query.kind, query.column, query.rotation))
+                .join(", ");
+            format!(
+                "{{\"name\": {}, \"description\": {}, \"degree\": {}, \"nodes\": {}, \"term_len\": {}, \"queries\": [{queries}]}}",
+                json_string(&metric.name),
+                json_string(&metric.description),
+                metric.degree,
+                metric.nodes,
+                metric.term_len
+            )
+        })
+        .join(",\n  ");
+    format!("[\n  {entries}\n]\n")
+}
+
+#[cfg(test)]
+mod test {
+    use halo2_proofs::circuit::{Layouter, SimpleFloorPlanner};
+    use halo2_proofs::plonk::Circuit;
+    use halo2_proofs::poly::Rotation;
+
+    use super::*;
+    use crate::extraction::ExtractionOptions;
+
+    // The gate a * b = 0 and a lookup of a in b
+    struct ProductCircuit;
+
+    impl Circuit<TermField> for ProductCircuit {
+        type Config = ();
+        type FloorPlanner = SimpleFloorPlanner;
+        type Params = ();
+
+        fn without_witnesses(&self) -> Self {
+            Self
+        }
+
+        fn configure(meta: &mut ConstraintSystem<TermField>) -> Self::Config {
+            let (a, b) = (meta.advice_column(), meta.advice_column());
+            meta.create_gate("product", |meta| vec![meta.query_advice(a, Rotation::cur()) * meta.query_advice(b, Rotation::next())]);
+            meta.lookup_any("a in b", |meta| vec![(meta.query_advice(a, Rotation::cur()), meta.query_advice(b, Rotation::cur()))]);
+        }
+
+        fn synthesize(&self, _: Self::Config, _: impl Layouter<TermField>) -> Result<(), halo2_frontend::plonk::Error> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_constraint_metrics() {
+        let (cs, extraction) = ExtractingAssignment::synthesize_circuit(&ProductCircuit, &ExtractionOptions::default()).unwrap();
+        let metrics = extraction.constraint_metrics(&cs);
+        let summary = metrics.iter().map(|metric| (metric.name.as_str(), metric.degree, metric.nodes, metric.term_len)).collect_vec();
+        let gate = "c.get_advice 0 row * c.get_advice 1 ((row + 1) % c.n)";
+        let lookup = "c.get_advice 0 row".len() + "c.get_advice 1 row".len();
+        assert_eq!(summary, vec![("gate_0", 2, 3, gate.len()), ("lookup_0", 1, 2, lookup)]);
+        let advice = |column, rotation| Query { kind: ColumnKind::Advice, column, rotation };
+        assert_eq!(metrics[0].queries, vec![advice(0, 0), advice(1, 1)]);
+    }
+}
@@ -0,0 +1,215 @@
+use halo2_proofs::plonk::{Any, Expression};
+
+use crate::extraction::ExtractingAssignment;
+use crate::field::TermField;
+
+pub mod boundary;
+pub mod metrics;
+pub mod selectors;
+pub mod unconstrained;
+pub mod unused;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ColumnKind {
+    Advice,
+    Fixed,
+    Instance,
+}
+
+impl From<&Any> for ColumnKind {
+    fn from(column_type: &Any) -> Self {
+        match column_type {
+            Any::Advice => ColumnKind::Advice,
+            Any::Fixed => ColumnKind::Fixed,
+            Any::Instance => ColumnKind::Instance,
+        }
+    }
+}
+
+// A cell queried by an expression, relative to the row the expression is evaluated at
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Query {
+    pub kind: ColumnKind,
+    pub column: usize,
+    pub rotation: i32,
+}
+
+// A factor of a whole expression which, when zero, makes the expression zero
+// e.g. the selector in q_enable * (a - b)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Guard {
+    Selector(usize),
+    Fixed(usize, i32),
+}
+
+pub fn queries(expr: &Expression<TermField>) -> Vec<Query> {
+    let mut res = vec![];
+    collect_queries(expr, &mut res);
+    res.sort();
+    res.dedup();
+    res
+}
+
+fn collect_queries(expr: &Expression<TermField>, res: &mut Vec<Query>) {
+    let mut push = |kind, column, rotation| res.push(Query { kind, column, rotation });
+    match expr {
+        Expression::Fixed(query) => push(ColumnKind::Fixed, query.column_index(), query.rotation().0),
+        Expression::Advice(query) => push(ColumnKind::Advice, query.column_index(), query.rotation().0),
+        Expression::Instance(query) => push(ColumnKind::Instance, query.column_index(), query.rotation().0),
+        Expression::Constant(_) | Expression::Selector(_) | Expression::Challenge(_) => {},
+        Expression::Negated(inner) | Expression::Scaled(inner, _) => collect_queries(inner, res),
+        Expression::Sum(lhs, rhs) | Expression::Product(lhs, rhs) => {
+            collect_queries(lhs, res);
+            collect_queries(rhs, res);
+        },
+    }
+}
+
+pub fn guards(expr: &Expression<TermField>) -> Vec<Guard> {
+    match expr {
+        Expression::Selector(selector) => vec![Guard::Selector(selector.index())],
+        Expression::Fixed(query) => vec![Guard::Fixed(query.column_index(), query.rotation().0)],
+        Expression::Negated(inner) | Expression::Scaled(inner, _) => guards(inner),
+        Expression::Product(lhs, rhs) => {
+            let mut res = guards(lhs);
+            res.extend(guards(rhs));
+            res
+        },
+        _ => vec![],
+    }
+}
+
+// Splits a polynomial of the shape s * body, as built by BaseConstraintBuilder::gate and condition,
+// into the selectors that are top level factors and the product of the remaining factors
+// Returns None if no selector is a top level factor
+pub fn split_selector_guards(expr: &Expression<TermField>) -> Option<(Vec<usize>, Expression<TermField>)> {
+    fn factors<'a>(expr: &'a Expression<TermField>, res: &mut Vec<&'a Expression<TermField>>) {
+        match expr {
+            Expression::Product(lhs, rhs) => {
+                factors(lhs, res);
+                factors(rhs, res);
+            },
+            _ => res.push(expr),
+        }
+    }
+    let mut all_factors = vec![];
+    factors(expr, &mut all_factors);
+
+    let (selectors, rest): (Vec<_>, Vec<_>) = all_factors.into_iter().partition(|factor| matches!(factor, Expression::Selector(_)));
+    if selectors.is_empty() {
+        return None;
+    }
+    let mut selectors = selectors
+        .into_iter()
+        .filter_map(|factor| match factor {
+            Expression::Selector(selector) => Some(selector.index()),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    selectors.sort();
+    selectors.dedup();
+    let body = rest
+        .into_iter()
+        .cloned()
+        .reduce(|lhs, rhs| Expression::Product(Box::new(lhs), Box::new(rhs)))
+        .unwrap_or_else(|| Expression::Constant(TermField::from(1u64)));
+    Some((selectors, body))
+}
+
+// The row a rotation refers to, or None if it would be negative
+pub fn rotate(row: usize, rotation: i32) -> Option<usize> {
+    row.checked_add_signed(rotation as isize)
+}
+
+impl ExtractingAssignment<TermField> {
+    // Whether the guard is nonzero at the row, using the recorded selectors and fixed values
+    // Unassigned fixed cells are treated as zero, as they are in halo2
+    pub fn guard_enabled(&self, guard: &Guard, row: usize) -> bool {
+        match guard {
+            Guard::Selector(selector) => self
+                .selectors
+                .get(selector)
+                .is_some_and(|rows| rows.contains_key(&row)),
+            Guard::Fixed(column, rotation) => rotate(row, *rotation)
+                .and_then(|row| self.fixed_value(*column, row))
+                .is_some_and(|value| value != "0"),
+        }
+    }
+
+    // The usable rows at which none of the guards of the expression are zero
+    pub fn active_rows(&self, expr: &Expression<TermField>) -> Vec<usize> {
+        let guards = guards(expr);
+
+        // Only rows where a selector is enabled can be active, so start from the sparsest selector if there is one
+        let candidates: Vec<usize> = guards
+            .iter()
+            .filter_map(|guard| match guard {
+                Guard::Selector(selector) => Some(self.selectors.get(selector)),
+                Guard::Fixed(_, _) => None,
+            })
+            .min_by_key(|rows| rows.map_or(0, |rows| rows.len()))
+            .map(|rows| rows.map(|rows| rows.keys().copied().collect()).unwrap_or_default())
+            .unwrap_or_else(|| (0..self.usable_rows()).collect());
+
+        candidates
+            .into_iter()
+            .filter(|row| guards.iter().all(|guard| self.guard_enabled(guard, *row)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use halo2_proofs::plonk::ConstraintSystem;
+    use halo2_proofs::poly::Rotation;
+
+    use super::*;
+    use crate::extraction::expression_to_value_string;
+
+    // Queries of advice columns 0 and 1 and of selectors 0 and 1
+    fn queries() -> [Expression<TermField>; 4] {
+        let mut cs = ConstraintSystem::<TermField>::default();
+        let (a, b) = (cs.advice_column(), cs.advice_column());
+        let (s0, s1) = (cs.selector(), cs.selector());
+        let mut queries = None;
+        cs.create_gate("queries", |meta| {
+            queries = Some([
+                meta.query_advice(a, Rotation::cur()),
+                meta.query_advice(b, Rotation::cur()),
+                meta.query_selector(s0),
+                meta.query_selector(s1),
+            ]);
+            vec![Expression::Constant(TermField::zero())]
+        });
+        queries.unwrap()
+    }
+
+    fn product(lhs: &Expression<TermField>, rhs: &Expression<TermField>) -> Expression<TermField> {
+        Expression::Product(Box::new(lhs.clone()), Box::new(rhs.clone()))
+    }
+
+    fn split(expr: &Expression<TermField>) -> Option<(Vec<usize>, String)> {
+        split_selector_guards(expr).map(|(selectors, body)| (selectors, expression_to_value_string(&body, "row")))
+    }
+
+    #[test]
+    fn test_split_selector_guards() {
+        let [a, b, s0, s1] = queries();
+        let sum = a.clone() + b.clone();
+
+        assert_eq!(split(&product(&s0, &sum)), Some((vec![0], "c.get_advice 0 row + c.get_advice 1 row".to_string())));
+        // Selectors are found at any depth of nested products, and the other factors keep their order
+        assert_eq!(
+            split(&product(&product(&s1, &a), &product(&b, &s0))),
+            Some((vec![0, 1], "c.get_advice 0 row * c.get_advice 1 row".to_string()))
+        );
+        // A selector that is a factor twice is only a guard once, which is equivalent as selectors are 0 or 1
+        assert_eq!(split(&product(&s0, &product(&s0, &a))), Some((vec![0], "c.get_advice 0 row".to_string())));
+        // A gate that is just a selector says the selector is never on
+        assert_eq!(split(&s0), Some((vec![0], "1".to_string())));
+        assert_eq!(split(&product(&a, &(s0 * b.clone()))), Some((vec![0], "c.get_advice 0 row * c.get_advice 1 row".to_string())));
+        assert_eq!(split(&product(&a, &b)), None);
+        // A selector inside a sum is not a factor of the whole polynomial
+        assert_eq!(split(&Expression::Sum(Box::new(sum), Box::new(product(&s1, &a)))), None);
+    }
+}
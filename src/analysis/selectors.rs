@@ -0,0 +1,176 @@
+use std::collections::BTreeSet;
+
+use itertools::Itertools;
+
+use crate::extraction::{ExtractingAssignment, GROUPING_SIZE};
+use crate::field::TermField;
+use crate::utils::group_values;
+
+// A column that is either on (1) or off (0) at every row the emitted model defines
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SelectorColumn {
+    Selector(usize),
+    // A fixed column only ever assigned 0 or 1, such as q_enable
+    Fixed(usize),
+}
+
+impl SelectorColumn {
+    fn name(&self) -> String {
+        match self {
+            SelectorColumn::Selector(col) => format!("selector_{col}"),
+            SelectorColumn::Fixed(col) => format!("fixed_{col}"),
+        }
+    }
+
+    fn func(&self) -> String {
+        match self {
+            SelectorColumn::Selector(col) => format!("selector_func_col_{col}"),
+            SelectorColumn::Fixed(col) => format!("fixed_func_col_{col}"),
+        }
+    }
+}
+
+// Whether two selector columns are ever on in the same row
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SelectorOverlap {
+    pub first: SelectorColumn,
+    pub second: SelectorColumn,
+    pub shared_rows: Vec<usize>,
+}
+
+impl ExtractingAssignment<TermField> {
+    // The selectors, and the fixed columns that behave like selectors, with the rows they are on at
+    // A fixed column is only included if it is assigned on every used row, as the model leaves unassigned cells arbitrary,
+    // and if print_fixed emits it as a single if-chain, so that the lemmas can unfold it
+    pub fn selector_columns(&self) -> Vec<(SelectorColumn, BTreeSet<usize>)> {
+        let usable_rows = self.usable_rows();
+        let selectors = self
+            .selectors
+            .iter()
+            .map(|(col, rows)| (SelectorColumn::Selector(*col), rows.keys().copied().collect()));
+        let fixed = self
+            .fixed
+            .iter()
+            .filter(|(col, rows)| {
+                !self.fixed_is_array(**col)
+                    && (0..usable_rows).all(|row| rows.contains_key(&row))
+                    && rows.values().all(|value| value == "0" || value == "1")
+                    && group_values(rows).len() <= GROUPING_SIZE
+            })
+            .map(|(col, rows)| {
+                let on_rows = rows.iter().filter(|(_, value)| *value == "1").map(|(row, _)| *row).collect();
+                (SelectorColumn::Fixed(*col), on_rows)
+            });
+        selectors.chain(fixed).collect_vec()
+    }
+
+    pub fn selector_overlaps(&self) -> Vec<SelectorOverlap> {
+        self.selector_columns()
+            .iter()
+            .tuple_combinations()
+            .map(|((first, first_rows), (second, second_rows))| SelectorOverlap {
+                first: *first,
+                second: *second,
+                shared_rows: first_rows.intersection(second_rows).copied().collect(),
+            })
+            .collect_vec()
+    }
+
+    // Emits a lemma for each pair of selector columns that are never on in the same row,
+    // and a comment listing the shared rows of each pair that are
+    pub fn print_selector_disjointness(&self) {
+        print!("{}", self.selector_disjointness());
+    }
+
+    // The Lean text print_selector_disjointness prints
+    pub(crate) fn selector_disjointness(&self) -> String {
+        let mut lines: Vec<String> = vec![];
+        let usable_rows = self.usable_rows();
+        let (circuit, _) = self.field_types();
+
+        lines.push("-- Selector disjointness:".to_string());
+        for overlap in self.selector_overlaps() {
+            let (first, second) = (overlap.first, overlap.second);
+            if !overlap.shared_rows.is_empty() {
+                let rows = overlap.shared_rows.iter().join(", ");
+                lines.push(format!("-- {} and {} are both on at rows {rows}", first.name(), second.name()));
+                continue;
+            }
+
+            // Fixed columns are only known on the used rows, so their lemmas are restricted to those
+            let has_fixed = matches!(first, SelectorColumn::Fixed(_)) || matches!(second, SelectorColumn::Fixed(_));
+            let bound = if has_fixed { format!(" (h_row: row < {usable_rows})") } else { String::new() };
+            lines.push(format!(
+                "lemma {}_{}_disjoint (c: {circuit}) (row: ℕ){bound} :",
+                first.name(),
+                second.name().trim_start_matches("selector_")
+            ));
+            lines.push(format!("  {} c row = 0 ∨ {} c row = 0 := by", first.func(), second.func()));
+            // Each branch of the if-chains either has one of the columns at 0, or contradictory row bounds
+            lines.push(format!(
+                "  simp only [{}, {}] <;> split_ifs <;> first | (left; rfl) | (right; rfl) | omega",
+                first.func(),
+                second.func()
+            ));
+        }
+        lines.join("\n") + "\n"
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use halo2_proofs::circuit::{Layouter, SimpleFloorPlanner};
+    use halo2_proofs::plonk::{Circuit, ConstraintSystem, Selector};
+
+    use super::*;
+    use crate::extraction::ExtractionOptions;
+
+    // s0 is on at row 0, s1 and s2 both at row 1
+    struct SelectorCircuit;
+
+    impl Circuit<TermField> for SelectorCircuit {
+        type Config = [Selector; 3];
+        type FloorPlanner = SimpleFloorPlanner;
+        type Params = ();
+
+        fn without_witnesses(&self) -> Self {
+            Self
+        }
+
+        fn configure(meta: &mut ConstraintSystem<TermField>) -> Self::Config {
+            [meta.selector(), meta.selector(), meta.selector()]
+        }
+
+        fn synthesize(&self, [s0, s1, s2]: Self::Config, mut layouter: impl Layouter<TermField>) -> Result<(), halo2_frontend::plonk::Error> {
+            layouter.assign_region(
+                || "selectors",
+                |mut region| {
+                    s0.enable(&mut region, 0)?;
+                    s1.enable(&mut region, 1)?;
+                    s2.enable(&mut region, 1)?;
+                    Ok(())
+                },
+            )
+        }
+    }
+
+    #[test]
+    fn test_selector_disjointness() {
+        let (_, extraction) = ExtractingAssignment::synthesize_circuit(&SelectorCircuit, &ExtractionOptions::default()).unwrap();
+        assert_eq!(
+            extraction.selector_disjointness(),
+            [
+                "-- Selector disjointness:",
+                "lemma selector_0_1_disjoint (c: ValidCircuit P P_Prime) (row: ℕ) :",
+                "  selector_func_col_0 c row = 0 ∨ selector_func_col_1 c row = 0 := by",
+                "  simp only [selector_func_col_0, selector_func_col_1] <;> split_ifs <;> first | (left; rfl) | (right; rfl) | omega",
+                "lemma selector_0_2_disjoint (c: ValidCircuit P P_Prime) (row: ℕ) :",
+                "  selector_func_col_0 c row = 0 ∨ selector_func_col_2 c row = 0 := by",
+                "  simp only [selector_func_col_0, selector_func_col_2] <;> split_ifs <;> first | (left; rfl) | (right; rfl) | omega",
+                "-- selector_1 and selector_2 are both on at rows 1",
+                "",
+            ]
+            .join("\n")
+        );
+    }
+}
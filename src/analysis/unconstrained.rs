@@ -0,0 +1,157 @@
+use std::collections::HashSet;
+
+use halo2_proofs::plonk::{Any, ConstraintSystem, Expression};
+use itertools::Itertools;
+
+use crate::analysis::{queries, rotate, ColumnKind};
+use crate::extraction::ExtractingAssignment;
+use crate::field::TermField;
+
+// An assigned advice cell that no active gate, lookup, shuffle or copy constraint mentions
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UnconstrainedCell {
+    pub column: usize,
+    pub row: usize,
+    pub column_annotation: Option<String>,
+    pub annotation: String,
+}
+
+impl ExtractingAssignment<TermField> {
+    pub fn unconstrained_advice_cells(&self, cs: &ConstraintSystem<TermField>) -> Vec<UnconstrainedCell> {
+        let mut constrained: HashSet<(usize, usize)> = HashSet::new();
+        let mut mark = |expr: &Expression<TermField>| {
+            let rows = self.active_rows(expr);
+            for query in queries(expr).iter().filter(|query| query.kind == ColumnKind::Advice) {
+                for row in &rows {
+                    if let Some(row) = rotate(*row, query.rotation) {
+                        constrained.insert((query.column, row));
+                    }
+                }
+            }
+        };
+
+        for gate in cs.gates() {
+            gate.polynomials().iter().for_each(&mut mark);
+        }
+        for lookup in cs.lookups() {
+            lookup.input_expressions().iter().chain(lookup.table_expressions()).for_each(&mut mark);
+        }
+        for shuffle in cs.shuffles() {
+            shuffle.input_expressions().iter().chain(shuffle.shuffle_expressions()).for_each(&mut mark);
+        }
+        for ((left_column, left_row), (right_column, right_row)) in &self.copies {
+            for (column, row) in [(left_column, left_row), (right_column, right_row)] {
+                if *column.column_type() == Any::Advice {
+                    constrained.insert((column.index(), *row));
+                }
+            }
+        }
+
+        self.advice_column_annotations
+            .iter()
+            .flat_map(|(column, (column_annotation, rows))| {
+                rows
+                    .iter()
+                    .filter(|(row, _)| !constrained.contains(&(*column, **row)))
+                    .map(|(row, annotation)| UnconstrainedCell {
+                        column: *column,
+                        row: *row,
+                        column_annotation: column_annotation.clone(),
+                        annotation: annotation.clone(),
+                    })
+            })
+            .collect_vec()
+    }
+
+    pub fn print_unconstrained_advice_cells(&self, cs: &ConstraintSystem<TermField>) {
+        let cells = self.unconstrained_advice_cells(cs);
+        println!("-- Unconstrained advice cells (assigned, but not mentioned by any active gate, lookup, shuffle or copy):");
+        if cells.is_empty() {
+            println!("--   None");
+        }
+
+        // Group consecutive rows of a column that share an annotation
+        let mut runs: Vec<(&UnconstrainedCell, usize)> = vec![];
+        for cell in &cells {
+            match runs.last_mut() {
+                Some((start, end)) if start.column == cell.column && *end + 1 == cell.row && start.annotation == cell.annotation => {
+                    *end = cell.row;
+                },
+                _ => runs.push((cell, cell.row)),
+            }
+        }
+        for (start, end) in runs {
+            let rows = if start.row == end {
+                format!("row {end}")
+            } else {
+                format!("rows {}-{end}", start.row)
+            };
+            let column = match &start.column_annotation {
+                Some(column_annotation) => format!("advice {} ({column_annotation})", start.column),
+                None => format!("advice {}", start.column),
+            };
+            println!("--WARNING: {column} {rows}: {}", start.annotation);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use halo2_proofs::circuit::{Layouter, SimpleFloorPlanner, Value};
+    use halo2_proofs::plonk::{Advice, Circuit, Column, Selector};
+    use halo2_proofs::poly::Rotation;
+
+    use super::*;
+    use crate::extraction::ExtractionOptions;
+
+    // a = b where s is enabled, and s * a is looked up in the advice column t
+    struct EqualityCircuit;
+
+    impl Circuit<TermField> for EqualityCircuit {
+        type Config = ([Column<Advice>; 3], Selector);
+        type FloorPlanner = SimpleFloorPlanner;
+        type Params = ();
+
+        fn without_witnesses(&self) -> Self {
+            Self
+        }
+
+        fn configure(meta: &mut ConstraintSystem<TermField>) -> Self::Config {
+            let advice = [meta.advice_column(), meta.advice_column(), meta.advice_column()];
+            let [a, b, t] = advice;
+            let s = meta.complex_selector();
+            meta.create_gate("eq", |meta| {
+                let s = meta.query_selector(s);
+                vec![s * (meta.query_advice(a, Rotation::cur()) - meta.query_advice(b, Rotation::cur()))]
+            });
+            meta.lookup_any("in t", |meta| {
+                let s = meta.query_selector(s);
+                vec![(s * meta.query_advice(a, Rotation::cur()), meta.query_advice(t, Rotation::cur()))]
+            });
+            (advice, s)
+        }
+
+        fn synthesize(&self, ([a, b, t], s): Self::Config, mut layouter: impl Layouter<TermField>) -> Result<(), halo2_frontend::plonk::Error> {
+            layouter.assign_region(
+                || "eq",
+                |mut region| {
+                    s.enable(&mut region, 0)?;
+                    for (name, column, row) in [("a", a, 0), ("b", b, 0), ("stray", a, 1), ("table", t, 0), ("table", t, 1)] {
+                        region.assign_advice(|| name, column, row, || Value::known(TermField::zero()))?;
+                    }
+                    Ok(())
+                },
+            )
+        }
+    }
+
+    #[test]
+    fn test_unconstrained_advice_cells() {
+        let (cs, extraction) = ExtractingAssignment::synthesize_circuit(&EqualityCircuit, &ExtractionOptions::default()).unwrap();
+        // s is off at row 1, so nothing mentions a there, while the table cells are constrained by the lookup alone
+        assert_eq!(
+            extraction.unconstrained_advice_cells(&cs),
+            vec![UnconstrainedCell { column: 0, row: 1, column_annotation: None, annotation: "stray".to_string() }]
+        );
+    }
+}
@@ -0,0 +1,200 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use halo2_proofs::plonk::{ConstraintSystem, Expression};
+use itertools::Itertools;
+
+use crate::analysis::{guards, queries, ColumnKind};
+use crate::extraction::ExtractingAssignment;
+use crate::field::TermField;
+
+// A column that no gate, lookup, shuffle or copy reads
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UnusedColumn {
+    pub kind: ColumnKind,
+    pub column: usize,
+    pub annotation: Option<String>,
+    // Whether the circuit assigns any cell of the column, which is wasted work if nothing reads it
+    pub assigned: bool,
+}
+
+// Dead weight in a circuit, such as the dummy columns of Challenges::construct
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct UnusedReport {
+    pub columns: Vec<UnusedColumn>,
+    pub selectors_never_enabled: Vec<usize>,
+    // Descriptions of gate polynomials, lookups and shuffles whose guards are never all on
+    pub dead_gates: Vec<String>,
+    pub dead_lookups: Vec<String>,
+    pub dead_shuffles: Vec<String>,
+}
+
+impl ExtractingAssignment<TermField> {
+    pub fn unused_report(&self, cs: &ConstraintSystem<TermField>) -> UnusedReport {
+        let exprs = cs
+            .gates()
+            .iter()
+            .flat_map(|gate| gate.polynomials())
+            .chain(cs.lookups().iter().flat_map(|lookup| lookup.input_expressions().iter().chain(lookup.table_expressions())))
+            .chain(cs.shuffles().iter().flat_map(|shuffle| shuffle.input_expressions().iter().chain(shuffle.shuffle_expressions())))
+            .collect_vec();
+
+        let mut read: BTreeSet<(ColumnKind, usize)> = exprs
+            .iter()
+            .flat_map(|expr| queries(expr))
+            .map(|query| (query.kind, query.column))
+            .collect();
+        for ((left_column, _), (right_column, _)) in &self.copies {
+            read.insert((left_column.column_type().into(), left_column.index()));
+            read.insert((right_column.column_type().into(), right_column.index()));
+        }
+
+        let mut columns = vec![];
+        let mut check_column = |kind: ColumnKind, count: usize, annotations: &BTreeMap<usize, (Option<String>, BTreeMap<usize, String>)>, assigned: &dyn Fn(usize) -> bool| {
+            for column in (0..count).filter(|column| !read.contains(&(kind, *column))) {
+                columns.push(UnusedColumn {
+                    kind,
+                    column,
+                    annotation: annotations.get(&column).and_then(|(annotation, _)| annotation.clone()),
+                    assigned: assigned(column),
+                });
+            }
+        };
+        check_column(ColumnKind::Advice, cs.num_advice_columns(), &self.advice_column_annotations, &|column| {
+            self.advice_column_annotations.get(&column).is_some_and(|(_, rows)| !rows.is_empty())
+        });
+        check_column(ColumnKind::Fixed, cs.num_fixed_columns(), &self.fixed_column_annotations, &|column| {
+            self.fixed.contains_key(&column) || self.fixed_fill.contains_key(&column)
+        });
+        // Instance cells are not assigned by the circuit, so an unread instance column is only reported as unused
+        check_column(ColumnKind::Instance, cs.num_instance_columns(), &self.instance_column_annotations, &|_| false);
+
+        let selectors_never_enabled = (0..cs.num_selectors())
+            .filter(|selector| !self.selectors.get(selector).is_some_and(|rows| !rows.is_empty()))
+            .collect_vec();
+
+        // Unguarded expressions are active on every row, so only guarded ones can be dead
+        let is_dead = |expr: &Expression<TermField>| !guards(expr).is_empty() && self.active_rows(expr).is_empty();
+
+        let dead_gates = cs
+            .gates()
+            .iter()
+            .flat_map(|gate| {
+                gate.polynomials()
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, polynomial)| is_dead(polynomial))
+                    .map(|(poly_idx, _)| format!("gate \"{}\" part {} {}", gate.name(), poly_idx+1, gate.constraint_name(poly_idx)))
+            })
+            .collect_vec();
+
+        // Only the inputs are guarded, the table may be active on every row
+        let dead_lookups = cs
+            .lookups()
+            .iter()
+            .enumerate()
+            .filter(|(_, lookup)| !lookup.input_expressions().is_empty() && lookup.input_expressions().iter().all(is_dead))
+            .map(|(idx, lookup)| format!("lookup number {} \"{}\"", idx+1, lookup.name()))
+            .collect_vec();
+
+        let dead_shuffles = cs
+            .shuffles()
+            .iter()
+            .filter(|shuffle| !shuffle.input_expressions().is_empty() && shuffle.input_expressions().iter().all(is_dead))
+            .map(|shuffle| format!("shuffle \"{}\"", shuffle.name()))
+            .collect_vec();
+
+        UnusedReport { columns, selectors_never_enabled, dead_gates, dead_lookups, dead_shuffles }
+    }
+
+    pub fn print_unused_report(&self, cs: &ConstraintSystem<TermField>) {
+        let report = self.unused_report(cs);
+        println!("-- Unused columns and dead constraints:");
+        if report == UnusedReport::default() {
+            println!("--   None");
+        }
+        for column in &report.columns {
+            let kind = match column.kind {
+                ColumnKind::Advice => "advice",
+                ColumnKind::Fixed => "fixed",
+                ColumnKind::Instance => "instance",
+            };
+            let annotation = column.annotation.as_ref().map_or(String::new(), |annotation| format!(" ({annotation})"));
+            let assigned = if column.assigned { "assigned but never read" } else { "never read" };
+            println!("--WARNING: {kind} column {}{annotation} is {assigned}", column.column);
+        }
+        for selector in &report.selectors_never_enabled {
+            println!("--WARNING: selector {selector} is never enabled");
+        }
+        for gate in &report.dead_gates {
+            println!("--WARNING: {gate} can never fire");
+        }
+        for lookup in &report.dead_lookups {
+            println!("--WARNING: {lookup} can never fire");
+        }
+        for shuffle in &report.dead_shuffles {
+            println!("--WARNING: {shuffle} can never fire");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use halo2_proofs::circuit::{Layouter, SimpleFloorPlanner, Value};
+    use halo2_proofs::plonk::{Advice, Circuit, Column, Selector};
+    use halo2_proofs::poly::Rotation;
+
+    use super::*;
+    use crate::extraction::ExtractionOptions;
+
+    // a = 0 where s is on, and t * a looked up in a, where t is never enabled, next to an assigned column nothing reads
+    struct DeadCircuit;
+
+    impl Circuit<TermField> for DeadCircuit {
+        type Config = (Column<Advice>, Column<Advice>, Selector);
+        type FloorPlanner = SimpleFloorPlanner;
+        type Params = ();
+
+        fn without_witnesses(&self) -> Self {
+            Self
+        }
+
+        fn configure(meta: &mut ConstraintSystem<TermField>) -> Self::Config {
+            let (a, unread) = (meta.advice_column(), meta.advice_column());
+            let (s, t) = (meta.complex_selector(), meta.complex_selector());
+            meta.create_gate("zero", |meta| vec![meta.query_selector(s) * meta.query_advice(a, Rotation::cur())]);
+            meta.lookup_any("dead", |meta| {
+                let a = meta.query_advice(a, Rotation::cur());
+                vec![(meta.query_selector(t) * a.clone(), a)]
+            });
+            (a, unread, s)
+        }
+
+        fn synthesize(&self, (a, unread, s): Self::Config, mut layouter: impl Layouter<TermField>) -> Result<(), halo2_frontend::plonk::Error> {
+            layouter.assign_region(
+                || "dead",
+                |mut region| {
+                    region.name_column(|| "unread", unread);
+                    s.enable(&mut region, 0)?;
+                    region.assign_advice(|| "a", a, 0, || Value::known(TermField::zero()))?;
+                    region.assign_advice(|| "unread", unread, 0, || Value::known(TermField::zero()))?;
+                    Ok(())
+                },
+            )
+        }
+    }
+
+    #[test]
+    fn test_unused_report() {
+        let (cs, extraction) = ExtractingAssignment::synthesize_circuit(&DeadCircuit, &ExtractionOptions::default()).unwrap();
+        assert_eq!(
+            extraction.unused_report(&cs),
+            UnusedReport {
+                columns: vec![UnusedColumn { kind: ColumnKind::Advice, column: 1, annotation: Some("unread".to_string()), assigned: true }],
+                selectors_never_enabled: vec![1],
+                dead_gates: vec![],
+                dead_lookups: vec!["lookup number 1 \"dead\"".to_string()],
+                dead_shuffles: vec![],
+            }
+        );
+    }
+}
@@ -0,0 +1,132 @@
+use std::collections::HashMap;
+
+use crate::printer::{BinaryOp, LeanTerm};
+
+// Sub-terms smaller than this (counted in nodes) are cheaper to repeat than to bind
+const MIN_SHARED_SIZE: usize = 3;
+
+// How repeated sub-expressions of gate polynomials are emitted
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CseMode {
+    // Every gate is printed as a single tree
+    #[default]
+    Off,
+    // Sub-expressions repeated within a gate are bound with let inside that gate
+    Let,
+    // Sub-expressions repeated anywhere in the gates become defs shared by all gates
+    Def,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum Node {
+    Text(String, u32),
+    Neg(usize),
+    Binary(BinaryOp, usize, usize),
+}
+
+// A hash-consed DAG of terms, in which structurally identical sub-terms share an id
+// Children are always inserted before their parents, so ids are in dependency order
+#[derive(Default)]
+pub struct TermDag {
+    nodes: Vec<Node>,
+    ids: HashMap<Node, usize>,
+    // Number of references to each node, from other nodes in the DAG or as a root
+    uses: Vec<usize>,
+    // Number of nodes in the tree each node stands for
+    sizes: Vec<usize>,
+}
+
+impl TermDag {
+    pub fn insert(&mut self, term: &LeanTerm) -> usize {
+        let (node, size) = match term {
+            LeanTerm::Text(text, precedence) => (Node::Text(text.clone(), *precedence), 1),
+            LeanTerm::Neg(term) => {
+                let id = self.insert(term);
+                (Node::Neg(id), self.sizes[id] + 1)
+            },
+            LeanTerm::Binary(op, lhs, rhs) => {
+                let lhs = self.insert(lhs);
+                let rhs = self.insert(rhs);
+                (Node::Binary(*op, lhs, rhs), self.sizes[lhs] + self.sizes[rhs] + 1)
+            },
+        };
+
+        if let Some(&id) = self.ids.get(&node) {
+            // The children were already referenced by the existing copy of this node,
+            // so the references just added for them are duplicates
+            match &node {
+                Node::Text(_, _) => {},
+                Node::Neg(child) => self.uses[*child] -= 1,
+                Node::Binary(_, lhs, rhs) => {
+                    self.uses[*lhs] -= 1;
+                    self.uses[*rhs] -= 1;
+                },
+            }
+            self.uses[id] += 1;
+            id
+        } else {
+            let id = self.nodes.len();
+            self.nodes.push(node.clone());
+            self.ids.insert(node, id);
+            self.uses.push(1);
+            self.sizes.push(size);
+            id
+        }
+    }
+
+    // The nodes worth binding to a name, in dependency order
+    pub fn shared(&self) -> Vec<usize> {
+        (0..self.nodes.len())
+            .filter(|&id| self.uses[id] > 1 && self.sizes[id] >= MIN_SHARED_SIZE)
+            .collect()
+    }
+
+    // Rebuilds the term for id, referring to the named nodes by name
+    // The node itself is always expanded, so that the body of a binding can be built from its own id
+    pub fn to_term(&self, id: usize, names: &HashMap<usize, String>) -> LeanTerm {
+        match &self.nodes[id] {
+            Node::Text(text, precedence) => LeanTerm::Text(text.clone(), *precedence),
+            Node::Neg(child) => LeanTerm::neg(self.child_term(*child, names)),
+            Node::Binary(op, lhs, rhs) => LeanTerm::binary(*op, self.child_term(*lhs, names), self.child_term(*rhs, names)),
+        }
+    }
+
+    fn child_term(&self, id: usize, names: &HashMap<usize, String>) -> LeanTerm {
+        match names.get(&id) {
+            Some(name) => LeanTerm::app(name.clone()),
+            None => self.to_term(id, names),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_nested_sharing() {
+        let a = || LeanTerm::app("c.get_advice 0 row");
+        let b = || LeanTerm::app("c.get_advice 1 row");
+        let c = || LeanTerm::app("c.get_advice 2 row");
+        let product = || LeanTerm::binary(BinaryOp::Mul, a(), b());
+        let sum = || LeanTerm::binary(BinaryOp::Add, product(), c());
+
+        // a * b only repeats because a * b + c does, so only the sum is bound
+        let mut dag = TermDag::default();
+        let root = dag.insert(&LeanTerm::binary(BinaryOp::Mul, sum(), sum()));
+        let shared = dag.shared();
+        assert_eq!(shared.len(), 1);
+        let names = HashMap::from([(shared[0], "cse_0".to_string())]);
+        assert_eq!(dag.to_term(root, &names).to_string(), "cse_0 * cse_0");
+        assert_eq!(dag.to_term(shared[0], &names).to_string(), "c.get_advice 0 row * c.get_advice 1 row + c.get_advice 2 row");
+
+        // Once a * b also occurs on its own it is bound too, before the sum that uses it
+        let mut dag = TermDag::default();
+        let root = dag.insert(&LeanTerm::binary(BinaryOp::Add, LeanTerm::binary(BinaryOp::Mul, sum(), sum()), product()));
+        let shared = dag.shared();
+        assert_eq!(shared.len(), 2);
+        let names = shared.iter().enumerate().map(|(idx, id)| (*id, format!("cse_{idx}"))).collect::<HashMap<_, _>>();
+        assert_eq!(dag.to_term(root, &names).to_string(), "cse_1 * cse_1 + cse_0");
+        assert_eq!(dag.to_term(shared[1], &names).to_string(), "cse_0 + c.get_advice 2 row");
+    }
+}
@@ -0,0 +1,370 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Display;
+
+use halo2_proofs::plonk::{Any, Circuit, Column, ConstraintSystem, Error, Expression};
+use itertools::Itertools;
+
+use crate::extraction::{expression_to_value_string, ExtractingAssignment, ExtractionOptions};
+use crate::field::TermField;
+use crate::utils::json_string;
+
+// The parts of an extraction that the emitted model depends on, keyed by name where the circuit provides one
+// so that two versions of a circuit can be compared regardless of the order things were declared in
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ExtractionSummary {
+    // "gate name/constraint name" to polynomial
+    pub gates: BTreeMap<String, String>,
+    // lookup name to "(inputs) ⊆ (table)"
+    pub lookups: BTreeMap<String, String>,
+    // shuffle name to "(inputs) ~ (shuffled)"
+    pub shuffles: BTreeMap<String, String>,
+    // selector index to enabled rows
+    pub selectors: BTreeMap<usize, BTreeSet<usize>>,
+    // fixed column to row to value, with fills expanded up to usable_rows
+    pub fixed: BTreeMap<usize, BTreeMap<usize, String>>,
+    // Each copy with its two sides ordered, printed as e.g. "advice 0 3"
+    pub copies: BTreeSet<(String, String)>,
+    pub usable_rows: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DiffKind {
+    Added,
+    Removed,
+    Changed,
+}
+
+impl DiffKind {
+    fn name(&self) -> &'static str {
+        match self {
+            DiffKind::Added => "added",
+            DiffKind::Removed => "removed",
+            DiffKind::Changed => "changed",
+        }
+    }
+}
+
+// One difference between an old and a new extraction
+// category is one of gate, lookup, shuffle, selector, fixed, copy and usable_rows
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DiffEntry {
+    pub kind: DiffKind,
+    pub category: &'static str,
+    pub key: String,
+    pub old: Option<String>,
+    pub new: Option<String>,
+}
+
+impl DiffEntry {
+    // A single line JSON object, for tooling that gates changes to verified circuits
+    pub fn to_json(&self) -> String {
+        let optional = |value: &Option<String>| value.as_ref().map_or("null".to_string(), |value| json_string(value));
+        format!(
+            "{{\"kind\": {}, \"category\": {}, \"key\": {}, \"old\": {}, \"new\": {}}}",
+            json_string(self.kind.name()),
+            json_string(self.category),
+            json_string(&self.key),
+            optional(&self.old),
+            optional(&self.new)
+        )
+    }
+}
+
+impl Display for DiffEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let show = |value: &Option<String>| value.clone().unwrap_or_else(|| "none".to_string());
+        match self.kind {
+            DiffKind::Added => write!(f, "+ {} {}: {}", self.category, self.key, show(&self.new)),
+            DiffKind::Removed => write!(f, "- {} {}: {}", self.category, self.key, show(&self.old)),
+            DiffKind::Changed => write!(f, "~ {} {}: {} => {}", self.category, self.key, show(&self.old), show(&self.new)),
+        }
+    }
+}
+
+// All entries as a JSON array, one entry per line
+pub fn diff_to_json(entries: &[DiffEntry]) -> String {
+    if entries.is_empty() {
+        return "[]".to_string();
+    }
+    format!("[\n  {}\n]", entries.iter().map(DiffEntry::to_json).join(",\n  "))
+}
+
+// Extracts both circuits and compares them, reporting what changed going from old to new
+pub fn diff_circuits<OldCircuit: Circuit<TermField>, NewCircuit: Circuit<TermField>>(
+    old: &OldCircuit,
+    new: &NewCircuit,
+    options: &ExtractionOptions,
+) -> Result<Vec<DiffEntry>, Error> {
+    let old = ExtractionSummary::from_circuit(old, options)?;
+    let new = ExtractionSummary::from_circuit(new, options)?;
+    Ok(old.diff(&new))
+}
+
+impl ExtractionSummary {
+    pub fn from_circuit<ConcreteCircuit: Circuit<TermField>>(circuit: &ConcreteCircuit, options: &ExtractionOptions) -> Result<Self, Error> {
+        let (cs, extraction) = ExtractingAssignment::synthesize_circuit(circuit, options)?;
+        Ok(Self::new(&cs, &extraction))
+    }
+
+    pub fn new(cs: &ConstraintSystem<TermField>, extraction: &ExtractingAssignment<TermField>) -> Self {
+        let usable_rows = extraction.usable_rows();
+        let tuple = |exprs: &[Expression<TermField>], row_name: &str| format!("({})", exprs.iter().map(|expr| expression_to_value_string(expr, row_name)).join(", "));
+
+        let mut gates = BTreeMap::new();
+        for gate in cs.gates() {
+            for (poly_idx, polynomial) in gate.polynomials().iter().enumerate() {
+                let key = format!("{}/{}", gate.name(), gate.constraint_name(poly_idx));
+                insert_unique(&mut gates, key, expression_to_value_string(polynomial, "row"));
+            }
+        }
+
+        let mut lookups = BTreeMap::new();
+        for lookup in cs.lookups() {
+            let value = format!("{} ⊆ {}", tuple(lookup.input_expressions(), "row"), tuple(lookup.table_expressions(), "lookup_row"));
+            insert_unique(&mut lookups, lookup.name().to_string(), value);
+        }
+
+        let mut shuffles = BTreeMap::new();
+        for shuffle in cs.shuffles() {
+            let value = format!("{} ~ {}", tuple(shuffle.input_expressions(), "row"), tuple(shuffle.shuffle_expressions(), "row"));
+            insert_unique(&mut shuffles, shuffle.name().to_string(), value);
+        }
+
+        let selectors = extraction
+            .selectors
+            .iter()
+            .map(|(selector, rows)| (*selector, rows.keys().copied().collect()))
+            .collect();
+
+        let fixed = extraction
+            .fixed
+            .keys()
+            .chain(extraction.fixed_fill.keys())
+            .unique()
+            .map(|col| {
+                let values = (0..usable_rows)
+                    .filter_map(|row| extraction.fixed_value(*col, row).map(|value| (row, value.clone())))
+                    .collect();
+                (*col, values)
+            })
+            .collect();
+
+        let copies = extraction
+            .copies
+            .iter()
+            .map(|(left, right)| {
+                let left = format_cell(left);
+                let right = format_cell(right);
+                if left <= right { (left, right) } else { (right, left) }
+            })
+            .collect();
+
+        Self { gates, lookups, shuffles, selectors, fixed, copies, usable_rows }
+    }
+
+    // The changes going from self to new
+    pub fn diff(&self, new: &Self) -> Vec<DiffEntry> {
+        let mut entries = vec![];
+        diff_maps(&mut entries, "gate", &self.gates, &new.gates);
+        diff_maps(&mut entries, "lookup", &self.lookups, &new.lookups);
+        diff_maps(&mut entries, "shuffle", &self.shuffles, &new.shuffles);
+
+        let selector_rows = |selectors: &BTreeMap<usize, BTreeSet<usize>>| selectors
+            .iter()
+            .map(|(selector, rows)| (selector.to_string(), format_row_runs(rows.iter().copied())))
+            .collect::<BTreeMap<_, _>>();
+        diff_maps(&mut entries, "selector", &selector_rows(&self.selectors), &selector_rows(&new.selectors));
+
+        for col in self.fixed.keys().chain(new.fixed.keys()).unique().sorted() {
+            let empty = BTreeMap::new();
+            let old_column = self.fixed.get(col).unwrap_or(&empty);
+            let new_column = new.fixed.get(col).unwrap_or(&empty);
+            diff_fixed_column(&mut entries, *col, old_column, new_column);
+        }
+
+        // A copy is identified by its two cells, so it can only be added or removed
+        let copy = |kind, (left, right): &(String, String)| {
+            let key = format!("{left} = {right}");
+            let value = Some(key.clone());
+            match kind {
+                DiffKind::Removed => DiffEntry { kind, category: "copy", key, old: value, new: None },
+                _ => DiffEntry { kind, category: "copy", key, old: None, new: value },
+            }
+        };
+        entries.extend(self.copies.difference(&new.copies).map(|pair| copy(DiffKind::Removed, pair)));
+        entries.extend(new.copies.difference(&self.copies).map(|pair| copy(DiffKind::Added, pair)));
+
+        if self.usable_rows != new.usable_rows {
+            entries.push(DiffEntry {
+                kind: DiffKind::Changed,
+                category: "usable_rows",
+                key: "usable_rows".to_string(),
+                old: Some(self.usable_rows.to_string()),
+                new: Some(new.usable_rows.to_string()),
+            });
+        }
+
+        entries
+    }
+}
+
+// Circuits may reuse a name, in which case later items are numbered in declaration order
+fn insert_unique(map: &mut BTreeMap<String, String>, key: String, value: String) {
+    let mut unique_key = key.clone();
+    let mut i = 1;
+    while map.contains_key(&unique_key) {
+        i += 1;
+        unique_key = format!("{key}#{i}");
+    }
+    map.insert(unique_key, value);
+}
+
+fn format_cell((column, row): &(Column<Any>, usize)) -> String {
+    let kind = match column.column_type() {
+        Any::Advice => "advice",
+        Any::Fixed => "fixed",
+        Any::Instance => "instance",
+    };
+    format!("{kind} {} {row}", column.index())
+}
+
+// e.g. "0-3, 7, 9-10"
+fn format_row_runs(rows: impl Iterator<Item = usize>) -> String {
+    let mut runs: Vec<(usize, usize)> = vec![];
+    for row in rows {
+        match runs.last_mut() {
+            Some((_, end)) if *end + 1 == row => *end = row,
+            _ => runs.push((row, row)),
+        }
+    }
+    runs
+        .iter()
+        .map(|(start, end)| if start == end { start.to_string() } else { format!("{start}-{end}") })
+        .join(", ")
+}
+
+fn diff_maps<K: Ord + Display>(entries: &mut Vec<DiffEntry>, category: &'static str, old: &BTreeMap<K, String>, new: &BTreeMap<K, String>) {
+    for key in old.keys().chain(new.keys()).unique().sorted() {
+        let (old_value, new_value) = (old.get(key), new.get(key));
+        let kind = match (old_value, new_value) {
+            (Some(old_value), Some(new_value)) if old_value == new_value => continue,
+            (Some(_), Some(_)) => DiffKind::Changed,
+            (Some(_), None) => DiffKind::Removed,
+            (None, _) => DiffKind::Added,
+        };
+        entries.push(DiffEntry { kind, category, key: key.to_string(), old: old_value.cloned(), new: new_value.cloned() });
+    }
+}
+
+// Large tables differ in long runs, so consecutive rows with the same old and new values are reported together
+fn diff_fixed_column(entries: &mut Vec<DiffEntry>, col: usize, old: &BTreeMap<usize, String>, new: &BTreeMap<usize, String>) {
+    // (start, end, old value, new value), end inclusive
+    let mut runs: Vec<(usize, usize, Option<&String>, Option<&String>)> = vec![];
+    for row in old.keys().chain(new.keys()).unique().sorted() {
+        let (old_value, new_value) = (old.get(row), new.get(row));
+        if old_value == new_value {
+            continue;
+        }
+        match runs.last_mut() {
+            Some((_, end, run_old, run_new)) if *end + 1 == *row && *run_old == old_value && *run_new == new_value => *end = *row,
+            _ => runs.push((*row, *row, old_value, new_value)),
+        }
+    }
+
+    for (start, end, old_value, new_value) in runs {
+        let kind = match (old_value, new_value) {
+            (Some(_), Some(_)) => DiffKind::Changed,
+            (Some(_), None) => DiffKind::Removed,
+            (None, _) => DiffKind::Added,
+        };
+        let rows = if start == end { format!("row {start}") } else { format!("rows {start}-{end}") };
+        entries.push(DiffEntry {
+            kind,
+            category: "fixed",
+            key: format!("column {col} {rows}"),
+            old: old_value.cloned(),
+            new: new_value.cloned(),
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use halo2_proofs::circuit::{Layouter, SimpleFloorPlanner};
+    use halo2_proofs::poly::Rotation;
+
+    use super::*;
+
+    // Two versions of a circuit with a named constraint and two gates that share a name
+    // Version 2 multiplies by and checks the third column where version 1 uses the second
+    struct VersionedCircuit<const VERSION: u8>;
+
+    impl<const VERSION: u8> Circuit<TermField> for VersionedCircuit<VERSION> {
+        type Config = ();
+        type FloorPlanner = SimpleFloorPlanner;
+        type Params = ();
+
+        fn without_witnesses(&self) -> Self {
+            Self
+        }
+
+        fn configure(meta: &mut ConstraintSystem<TermField>) -> Self::Config {
+            let (a, b, c) = (meta.advice_column(), meta.advice_column(), meta.advice_column());
+            let changed = if VERSION == 1 { b } else { c };
+            meta.create_gate("mul", |meta| vec![("product", meta.query_advice(a, Rotation::cur()) * meta.query_advice(changed, Rotation::cur()))]);
+            meta.create_gate("same", |meta| vec![meta.query_advice(a, Rotation::cur())]);
+            meta.create_gate("same", |meta| vec![meta.query_advice(changed, Rotation::cur())]);
+        }
+
+        fn synthesize(&self, _: Self::Config, _: impl Layouter<TermField>) -> Result<(), halo2_frontend::plonk::Error> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_circuit_diff() {
+        let old = ExtractionSummary::from_circuit(&VersionedCircuit::<1>, &ExtractionOptions::default()).unwrap();
+        assert_eq!(old.gates.keys().collect_vec(), vec!["mul/product", "same/", "same/#2"]);
+        let diff = diff_circuits(&VersionedCircuit::<1>, &VersionedCircuit::<2>, &ExtractionOptions::default()).unwrap();
+        // The first gate named same is unchanged, so only the second is reported
+        assert_eq!(
+            diff.iter().map(ToString::to_string).collect_vec(),
+            vec![
+                "~ gate mul/product: c.get_advice 0 row * c.get_advice 1 row => c.get_advice 0 row * c.get_advice 2 row",
+                "~ gate same/#2: c.get_advice 1 row => c.get_advice 2 row",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_summary_diff() {
+        let old = ExtractionSummary {
+            gates: BTreeMap::from([("add/".to_string(), "c.get_advice 0 row".to_string())]),
+            selectors: BTreeMap::from([(0, BTreeSet::from([0, 1, 2]))]),
+            fixed: BTreeMap::from([(0, (0..8).map(|row| (row, "1".to_string())).collect())]),
+            usable_rows: 8,
+            ..Default::default()
+        };
+        let mut new = old.clone();
+        new.gates.insert("mul/".to_string(), "c.get_advice 1 row".to_string());
+        new.selectors.insert(0, BTreeSet::from([0, 1]));
+        for row in 4..8 {
+            new.fixed.get_mut(&0).unwrap().insert(row, "2".to_string());
+        }
+
+        assert!(old.diff(&old).is_empty());
+        let diff = old.diff(&new);
+        assert_eq!(
+            diff.iter().map(ToString::to_string).collect_vec(),
+            vec![
+                "+ gate mul/: c.get_advice 1 row",
+                "~ selector 0: 0-2 => 0-1",
+                "~ fixed column 0 rows 4-7: 1 => 2",
+            ]
+        );
+        assert_eq!(
+            diff[1].to_json(),
+            "{\"kind\": \"changed\", \"category\": \"selector\", \"key\": \"0\", \"old\": \"0-2\", \"new\": \"0-1\"}"
+        );
+    }
+}
@@ -0,0 +1,352 @@
+use std::collections::BTreeSet;
+use std::fmt::Display;
+use std::marker::PhantomData;
+
+use ff::FromUniformBytes;
+use halo2_proofs::{
+    arithmetic::Field,
+    circuit::Value,
+    dev::{CellValue, MockProver},
+    plonk::{Advice, Any, Assigned, Assignment, Challenge, Circuit, Column, ConstraintSystem, Error, Fixed, FloorPlanner, Instance, Selector},
+};
+use itertools::Itertools;
+
+use crate::analysis::ColumnKind;
+use crate::extraction::{ExtractingAssignment, ExtractionOptions};
+use crate::field::TermField;
+
+// (column kind, column index, row)
+pub type LayoutCell = (ColumnKind, usize, usize);
+
+// A difference between the layout seen by the extractor and the layout seen by MockProver
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LayoutMismatch {
+    Fixed { column: usize, row: usize, extracted: Option<String>, mock: Option<String> },
+    Selector { selector: usize, row: usize, extracted: bool, mock: bool },
+    Copy { left: LayoutCell, right: LayoutCell, in_extraction: bool },
+    UsedRows { extracted: usize, mock: usize },
+}
+
+impl Display for LayoutMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let show = |value: &Option<String>| value.clone().unwrap_or_else(|| "unassigned".to_string());
+        match self {
+            LayoutMismatch::Fixed { column, row, extracted, mock } =>
+                write!(f, "fixed {column} row {row}: extracted {}, mock prover {}", show(extracted), show(mock)),
+            LayoutMismatch::Selector { selector, row, extracted, mock } =>
+                write!(f, "selector {selector} row {row}: extracted {}, mock prover {}", *extracted as u8, *mock as u8),
+            LayoutMismatch::Copy { left, right, in_extraction } => {
+                let (found, missing) = if *in_extraction { ("extracted", "mock prover") } else { ("mock prover", "extracted") };
+                write!(f, "copy {left:?} = {right:?} is in the {found} layout but not the {missing} layout")
+            },
+            LayoutMismatch::UsedRows { extracted, mock } =>
+                write!(f, "used rows: extracted {extracted}, mock prover {mock}"),
+        }
+    }
+}
+
+// Synthesizes the same circuit once symbolically and once over a concrete field, and compares the layouts
+// The two circuits should be instances of one circuit type that is generic over the field
+// Fixed values that depend on symbols cannot be compared, and are skipped
+pub fn compare_with_mock_prover<F, SymbolicCircuit, ConcreteCircuit>(
+    k: u32,
+    symbolic: &SymbolicCircuit,
+    concrete: &ConcreteCircuit,
+    instance: Vec<Vec<F>>,
+) -> Result<Vec<LayoutMismatch>, Error>
+where
+    F: FromUniformBytes<64> + Ord,
+    SymbolicCircuit: Circuit<TermField>,
+    ConcreteCircuit: Circuit<F>,
+{
+    let (cs, extraction) = ExtractingAssignment::synthesize_circuit(symbolic, &ExtractionOptions::default())?;
+    let mock = MockProver::run(k, concrete, instance)?;
+    let rows = mock.usable_rows().clone();
+    let mut mismatches = vec![];
+
+    // MockProver appends the compressed selectors as extra fixed columns, so only the circuit's own columns are compared
+    for column in 0..cs.num_fixed_columns() {
+        for row in rows.clone() {
+            let mock_value = match mock.fixed()[column][row] {
+                CellValue::Assigned(value) => Some(value),
+                _ => None,
+            };
+            let extracted = extraction.fixed_value(column, row);
+            let extracted_value = match extracted {
+                Some(text) => match TermField::from(text).to_concrete::<F>() {
+                    Some(value) => Some(value),
+                    None => continue,
+                },
+                None => None,
+            };
+            if extracted_value != mock_value {
+                mismatches.push(LayoutMismatch::Fixed {
+                    column,
+                    row,
+                    extracted: extracted.cloned(),
+                    mock: mock_value.map(|value| format!("{value:?}")),
+                });
+            }
+        }
+    }
+
+    for (selector, enabled) in mock.selectors().iter().enumerate() {
+        for row in rows.clone() {
+            let extracted = extraction
+                .selectors
+                .get(&selector)
+                .is_some_and(|selector_rows| selector_rows.contains_key(&row));
+            if extracted != enabled[row] {
+                mismatches.push(LayoutMismatch::Selector { selector, row, extracted, mock: enabled[row] });
+            }
+        }
+    }
+
+    let extracted_copies = normalize_copies(&extraction.copies);
+    let mock_copies = normalize_copies(&record_copies(concrete)?);
+    for (left, right) in extracted_copies.difference(&mock_copies) {
+        mismatches.push(LayoutMismatch::Copy { left: *left, right: *right, in_extraction: true });
+    }
+    for (left, right) in mock_copies.difference(&extracted_copies) {
+        mismatches.push(LayoutMismatch::Copy { left: *left, right: *right, in_extraction: false });
+    }
+
+    // Both sides count the rows of assigned advice cells, enabled selectors and copies,
+    // as fills and instance queries are handled differently by the two
+    let extracted_used_rows = used_rows(
+        extraction.advice_column_annotations.values().flat_map(|(_, rows)| rows.keys().copied())
+            .chain(extraction.selectors.values().flat_map(|rows| rows.keys().copied()))
+            .chain(extracted_copies.iter().flat_map(|(left, right)| [left.2, right.2]))
+    );
+    let mock_used_rows = used_rows(
+        mock.advice().iter().flat_map(|column| column.iter().positions(|cell| matches!(cell, CellValue::Assigned(_))))
+            .chain(mock.selectors().iter().flat_map(|column| column.iter().positions(|enabled| *enabled)))
+            .chain(mock_copies.iter().flat_map(|(left, right)| [left.2, right.2]))
+    );
+    if extracted_used_rows != mock_used_rows {
+        mismatches.push(LayoutMismatch::UsedRows { extracted: extracted_used_rows, mock: mock_used_rows });
+    }
+
+    Ok(mismatches)
+}
+
+fn used_rows(rows: impl Iterator<Item = usize>) -> usize {
+    rows.max().map_or(0, |row| row + 1)
+}
+
+// Copies are symmetric, so each pair is ordered before comparing
+fn normalize_copies(copies: &[((Column<Any>, usize), (Column<Any>, usize))]) -> BTreeSet<(LayoutCell, LayoutCell)> {
+    copies
+        .iter()
+        .map(|((left_column, left_row), (right_column, right_row))| {
+            let left = (ColumnKind::from(left_column.column_type()), left_column.index(), *left_row);
+            let right = (ColumnKind::from(right_column.column_type()), right_column.index(), *right_row);
+            if left <= right { (left, right) } else { (right, left) }
+        })
+        .collect()
+}
+
+// Synthesizes the concrete circuit with the same floor planner as MockProver, recording only the copies
+fn record_copies<F: Field, ConcreteCircuit: Circuit<F>>(circuit: &ConcreteCircuit) -> Result<Vec<((Column<Any>, usize), (Column<Any>, usize))>, Error> {
+    let mut cs = ConstraintSystem::default();
+    let config = ConcreteCircuit::configure_with_params(&mut cs, circuit.params());
+    let mut recorder = CopyRecorder { copies: vec![], _marker: PhantomData };
+    ConcreteCircuit::FloorPlanner::synthesize(&mut recorder, circuit, config, cs.constants().clone())?;
+    Ok(recorder.copies)
+}
+
+struct CopyRecorder<F: Field> {
+    copies: Vec<((Column<Any>, usize), (Column<Any>, usize))>,
+    _marker: PhantomData<F>,
+}
+
+impl<F: Field> Assignment<F> for CopyRecorder<F> {
+    fn enter_region<NR, N>(&mut self, _name_fn: N)
+    where
+        NR: Into<String>,
+        N: FnOnce() -> NR,
+    {
+    }
+
+    fn exit_region(&mut self) {}
+
+    fn enable_selector<A, AR>(
+        &mut self,
+        _annotation: A,
+        _selector: &Selector,
+        _row: usize,
+    ) -> Result<(), halo2_frontend::plonk::Error>
+    where
+        A: FnOnce() -> AR,
+        AR: Into<String>,
+    {
+        Ok(())
+    }
+
+    fn query_instance(
+        &self,
+        _column: Column<Instance>,
+        _row: usize,
+    ) -> Result<Value<F>, halo2_frontend::plonk::Error> {
+        Ok(Value::unknown())
+    }
+
+    fn assign_advice<V, VR, A, AR>(
+        &mut self,
+        _annotation: A,
+        _column: Column<Advice>,
+        _row: usize,
+        _to: V,
+    ) -> Result<(), halo2_frontend::plonk::Error>
+    where
+        V: FnOnce() -> Value<VR>,
+        VR: Into<Assigned<F>>,
+        A: FnOnce() -> AR,
+        AR: Into<String>,
+    {
+        Ok(())
+    }
+
+    fn assign_fixed<V, VR, A, AR>(
+        &mut self,
+        _annotation: A,
+        _column: Column<Fixed>,
+        _row: usize,
+        _to: V,
+    ) -> Result<(), halo2_frontend::plonk::Error>
+    where
+        V: FnOnce() -> Value<VR>,
+        VR: Into<Assigned<F>>,
+        A: FnOnce() -> AR,
+        AR: Into<String>,
+    {
+        Ok(())
+    }
+
+    fn copy(
+        &mut self,
+        left_column: Column<Any>,
+        left_row: usize,
+        right_column: Column<Any>,
+        right_row: usize,
+    ) -> Result<(), halo2_frontend::plonk::Error> {
+        self.copies.push(((left_column, left_row), (right_column, right_row)));
+        Ok(())
+    }
+
+    fn fill_from_row(
+        &mut self,
+        _column: Column<Fixed>,
+        _row: usize,
+        _to: Value<Assigned<F>>,
+    ) -> Result<(), halo2_frontend::plonk::Error> {
+        Ok(())
+    }
+
+    fn push_namespace<NR, N>(&mut self, _name_fn: N)
+    where
+        NR: Into<String>,
+        N: FnOnce() -> NR,
+    {
+    }
+
+    fn pop_namespace(&mut self, _gadget_name: Option<String>) {}
+
+    fn annotate_column<A, AR>(&mut self, _annotation: A, _column: Column<Any>)
+    where
+        A: FnOnce() -> AR,
+        AR: Into<String>,
+    {
+    }
+
+    fn get_challenge(&self, _challenge: Challenge) -> Value<F> {
+        Value::unknown()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use halo2_proofs::{
+        circuit::{Layouter, SimpleFloorPlanner},
+        poly::Rotation,
+    };
+    use halo2curves::bn256::Fr;
+
+    use super::*;
+
+    #[derive(Clone, Debug)]
+    pub(crate) struct TestCircuitConfig {
+        advice: [Column<Advice>; 3],
+        fixed: Column<Fixed>,
+        q_add: Selector,
+    }
+
+    pub(crate) struct TestCircuit<F: Field> {
+        pub(crate) a: Value<F>,
+        pub(crate) b: Value<F>,
+        // Added to the first sum, so that anything but zero gives a witness that breaks the add gate
+        pub(crate) tamper: F,
+    }
+
+    impl<F: Field> Circuit<F> for TestCircuit<F> {
+        type Config = TestCircuitConfig;
+        type FloorPlanner = SimpleFloorPlanner;
+        type Params = ();
+
+        fn without_witnesses(&self) -> Self {
+            Self { a: Value::unknown(), b: Value::unknown(), tamper: self.tamper }
+        }
+
+        fn configure(meta: &mut ConstraintSystem<F>) -> Self::Config {
+            let advice = [(); 3].map(|_| meta.advice_column());
+            for column in advice {
+                meta.enable_equality(column);
+            }
+            let constants = meta.fixed_column();
+            meta.enable_constant(constants);
+            let fixed = meta.fixed_column();
+            let q_add = meta.selector();
+
+            meta.create_gate("add", |meta| {
+                let q_add = meta.query_selector(q_add);
+                let [a, b, c] = advice.map(|column| meta.query_advice(column, Rotation::cur()));
+                let fixed = meta.query_fixed(fixed, Rotation::cur());
+                vec![q_add * (a + b + fixed - c)]
+            });
+
+            TestCircuitConfig { advice, fixed, q_add }
+        }
+
+        fn synthesize(&self, config: Self::Config, mut layouter: impl Layouter<F>) -> Result<(), halo2_frontend::plonk::Error> {
+            let c = layouter.assign_region(
+                || "first addition",
+                |mut region| {
+                    config.q_add.enable(&mut region, 0)?;
+                    region.assign_advice(|| "a", config.advice[0], 0, || self.a)?;
+                    region.assign_advice(|| "b", config.advice[1], 0, || self.b)?;
+                    region.assign_fixed(|| "offset", config.fixed, 0, || Value::known(F::ZERO))?;
+                    region.assign_advice(|| "c", config.advice[2], 0, || self.a + self.b + Value::known(self.tamper))
+                },
+            )?;
+            layouter.assign_region(
+                || "second addition",
+                |mut region| {
+                    config.q_add.enable(&mut region, 0)?;
+                    c.copy_advice(|| "a", &mut region, config.advice[0], 0)?;
+                    region.assign_advice_from_constant(|| "b", config.advice[1], 0, F::ONE)?;
+                    region.assign_fixed(|| "offset", config.fixed, 0, || Value::known(F::ONE + F::ONE))?;
+                    region.assign_advice(|| "c", config.advice[2], 0, || c.value().copied() + Value::known(F::ONE + F::ONE + F::ONE))
+                },
+            )?;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_layout_matches_mock_prover() {
+        let symbolic = TestCircuit { a: Value::known(TermField::from(2u64)), b: Value::known(TermField::from(3u64)), tamper: TermField::ZERO };
+        let concrete = TestCircuit { a: Value::known(Fr::from(2)), b: Value::known(Fr::from(3)), tamper: Fr::ZERO };
+        let mismatches = compare_with_mock_prover(4, &symbolic, &concrete, vec![]).unwrap();
+        assert!(mismatches.is_empty(), "{}", mismatches.iter().join("\n"));
+    }
+}
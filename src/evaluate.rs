@@ -0,0 +1,238 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Display;
+
+use ff::FromUniformBytes;
+use halo2_proofs::{
+    dev::{CellValue, InstanceValue, MockProver},
+    plonk::{Any, Circuit, Error, Expression},
+};
+use itertools::Itertools;
+
+use crate::extraction::{shuffle_name, ExtractingAssignment, ExtractionOptions};
+use crate::field::TermField;
+
+// Whether one emitted proposition (gate_N, copy_N, lookup_N or shuffle_...) holds for a concrete witness
+// Rows are left out of both lists when the proposition holds there
+// A row is undetermined when the result depends on a challenge, a symbol or a blinding value
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PropositionResult {
+    pub name: String,
+    pub failing_rows: Vec<usize>,
+    pub undetermined_rows: Vec<usize>,
+}
+
+impl PropositionResult {
+    fn new(name: String) -> Self {
+        Self { name, failing_rows: vec![], undetermined_rows: vec![] }
+    }
+
+    pub fn holds(&self) -> bool {
+        self.failing_rows.is_empty() && self.undetermined_rows.is_empty()
+    }
+}
+
+impl Display for PropositionResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.holds() {
+            return write!(f, "{}: holds", self.name);
+        }
+        write!(f, "{}:", self.name)?;
+        if !self.failing_rows.is_empty() {
+            write!(f, " fails at rows {}", self.failing_rows.iter().join(", "))?;
+        }
+        if !self.undetermined_rows.is_empty() {
+            write!(f, " undetermined at rows {}", self.undetermined_rows.iter().join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+// Evaluates the extracted model against the witness of a MockProver run of the same circuit over a concrete field
+// Selectors and fixed cells come from the extraction, advice and instance cells from the MockProver
+// Fixed cells the extraction leaves unassigned take the MockProver's value, as FixedUnassigned is arbitrary
+// If an honest witness fails, either the extraction or the Lean semantics of the model is wrong
+pub fn evaluate_extraction<F, SymbolicCircuit, ConcreteCircuit>(
+    k: u32,
+    symbolic: &SymbolicCircuit,
+    concrete: &ConcreteCircuit,
+    instance: Vec<Vec<F>>,
+    options: &ExtractionOptions,
+) -> Result<Vec<PropositionResult>, Error>
+where
+    F: FromUniformBytes<64> + Ord,
+    SymbolicCircuit: Circuit<TermField>,
+    ConcreteCircuit: Circuit<F>,
+{
+    let (cs, extraction) = ExtractingAssignment::synthesize_circuit(symbolic, options)?;
+    let mock = MockProver::run(k, concrete, instance)?;
+    let witness = Witness {
+        extraction: &extraction,
+        mock: &mock,
+        n: 1 << k,
+        usable_rows: mock.usable_rows().end,
+    };
+
+    let mut results = vec![];
+
+    let gates = extraction.emitted_gates(&cs);
+    for (idx, gate) in gates.iter().filter(|gate| gate.trivial.is_none()).enumerate() {
+        let mut result = PropositionResult::new(format!("gate_{idx}"));
+        for row in 0..witness.n {
+            match witness.evaluate(&gate.polynomial, row) {
+                Some(value) if value.is_zero_vartime() => {},
+                Some(_) => result.failing_rows.push(row),
+                None => result.undetermined_rows.push(row),
+            }
+        }
+        results.push(result);
+    }
+
+    for (idx, ((left_column, left_row), (right_column, right_row))) in extraction.copies.iter().enumerate() {
+        // Copies are not quantified over rows, so the row of the left cell is reported
+        let mut result = PropositionResult::new(format!("copy_{idx}"));
+        let left = witness.cell(left_column.column_type(), left_column.index(), *left_row);
+        let right = witness.cell(right_column.column_type(), right_column.index(), *right_row);
+        match (left, right) {
+            (Some(left), Some(right)) if left == right => {},
+            (Some(_), Some(_)) => result.failing_rows.push(*left_row),
+            _ => result.undetermined_rows.push(*left_row),
+        }
+        results.push(result);
+    }
+
+    for (idx, lookup) in cs.lookups().iter().enumerate() {
+        let mut result = PropositionResult::new(format!("lookup_{idx}"));
+        let mut table = BTreeSet::new();
+        let mut table_complete = true;
+        for lookup_row in 0..witness.usable_rows {
+            match witness.evaluate_tuple(lookup.table_expressions(), lookup_row) {
+                Some(tuple) => { table.insert(tuple); },
+                None => table_complete = false,
+            }
+        }
+        for row in 0..witness.usable_rows {
+            match witness.evaluate_tuple(lookup.input_expressions(), row) {
+                Some(tuple) if table.contains(&tuple) => {},
+                Some(_) if table_complete => result.failing_rows.push(row),
+                _ => result.undetermined_rows.push(row),
+            }
+        }
+        results.push(result);
+    }
+
+    for shuffle in cs.shuffles() {
+        let mut result = PropositionResult::new(shuffle_name(shuffle.name()));
+        // The shuffled rows must be a permutation of the input rows, so compare them as multisets
+        let mut remaining: BTreeMap<Vec<F>, usize> = BTreeMap::new();
+        let mut shuffled_complete = true;
+        for row in 0..witness.usable_rows {
+            match witness.evaluate_tuple(shuffle.shuffle_expressions(), row) {
+                Some(tuple) => *remaining.entry(tuple).or_default() += 1,
+                None => shuffled_complete = false,
+            }
+        }
+        for row in 0..witness.usable_rows {
+            match witness.evaluate_tuple(shuffle.input_expressions(), row) {
+                Some(tuple) if remaining.get(&tuple).is_some_and(|count| *count > 0) => {
+                    *remaining.get_mut(&tuple).expect("Count checked above") -= 1;
+                },
+                Some(_) if shuffled_complete => result.failing_rows.push(row),
+                _ => result.undetermined_rows.push(row),
+            }
+        }
+        results.push(result);
+    }
+
+    Ok(results)
+}
+
+struct Witness<'a, F: FromUniformBytes<64> + Ord> {
+    extraction: &'a ExtractingAssignment<TermField>,
+    mock: &'a MockProver<F>,
+    n: usize,
+    usable_rows: usize,
+}
+
+impl<'a, F: FromUniformBytes<64> + Ord> Witness<'a, F> {
+    // Rotations wrap around the table, as they do in the emitted model
+    fn rotate(&self, row: usize, rotation: i32) -> usize {
+        (row as i64 + rotation as i64).rem_euclid(self.n as i64) as usize
+    }
+
+    fn cell(&self, column_type: &Any, column: usize, row: usize) -> Option<F> {
+        match column_type {
+            Any::Advice => match self.mock.advice()[column][row] {
+                CellValue::Assigned(value) => Some(value),
+                CellValue::Unassigned => Some(F::ZERO),
+                CellValue::Poison(_) => None,
+            },
+            Any::Fixed => match self.extraction.fixed_value(column, row) {
+                Some(value) => TermField::from(value).to_concrete(),
+                None => match self.mock.fixed()[column][row] {
+                    CellValue::Assigned(value) => Some(value),
+                    _ => Some(F::ZERO),
+                },
+            },
+            Any::Instance => match self.mock.instance()[column][row] {
+                InstanceValue::Assigned(value) => Some(value),
+                InstanceValue::Padding => Some(F::ZERO),
+            },
+        }
+    }
+
+    fn evaluate(&self, expr: &Expression<TermField>, row: usize) -> Option<F> {
+        let product = |lhs: Option<F>, rhs: Option<F>| match (lhs, rhs) {
+            (Some(lhs), Some(rhs)) => Some(lhs * rhs),
+            // Zero times anything is zero, even if the other side is unknown
+            (Some(zero), None) | (None, Some(zero)) if zero.is_zero_vartime() => Some(F::ZERO),
+            _ => None,
+        };
+        match expr {
+            Expression::Constant(value) => value.to_concrete(),
+            Expression::Selector(selector) => {
+                let enabled = self.extraction
+                    .selectors
+                    .get(&selector.index())
+                    .is_some_and(|rows| rows.contains_key(&row));
+                Some(if enabled { F::ONE } else { F::ZERO })
+            },
+            Expression::Fixed(query) => self.cell(&Any::Fixed, query.column_index(), self.rotate(row, query.rotation().0)),
+            Expression::Advice(query) => self.cell(&Any::Advice, query.column_index(), self.rotate(row, query.rotation().0)),
+            Expression::Instance(query) => self.cell(&Any::Instance, query.column_index(), self.rotate(row, query.rotation().0)),
+            Expression::Challenge(_) => None,
+            Expression::Negated(inner) => self.evaluate(inner, row).map(|value| -value),
+            Expression::Sum(lhs, rhs) => Some(self.evaluate(lhs, row)? + self.evaluate(rhs, row)?),
+            Expression::Product(lhs, rhs) => product(self.evaluate(lhs, row), self.evaluate(rhs, row)),
+            Expression::Scaled(inner, factor) => product(self.evaluate(inner, row), factor.to_concrete()),
+        }
+    }
+
+    fn evaluate_tuple(&self, exprs: &[Expression<TermField>], row: usize) -> Option<Vec<F>> {
+        exprs.iter().map(|expr| self.evaluate(expr, row)).collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use halo2_proofs::{arithmetic::Field, circuit::Value};
+    use halo2curves::bn256::Fr;
+
+    use super::*;
+    use crate::differential::test::TestCircuit;
+
+    #[test]
+    fn test_evaluate_test_circuit() {
+        let symbolic = TestCircuit { a: Value::known(TermField::from(2u64)), b: Value::known(TermField::from(3u64)), tamper: TermField::ZERO };
+        let honest = TestCircuit { a: Value::known(Fr::from(2)), b: Value::known(Fr::from(3)), tamper: Fr::ZERO };
+        let results = evaluate_extraction(4, &symbolic, &honest, vec![], &ExtractionOptions::default()).unwrap();
+        assert!(results.iter().any(|result| result.name == "gate_0"));
+        assert!(results.iter().any(|result| result.name.starts_with("copy_")));
+        assert!(results.iter().all(PropositionResult::holds), "{}", results.iter().join("\n"));
+
+        // Only the first sum is off, and the second region copies it, so only the add gate at row 0 fails
+        let tampered = TestCircuit { tamper: Fr::ONE, ..honest };
+        let results = evaluate_extraction(4, &symbolic, &tampered, vec![], &ExtractionOptions::default()).unwrap();
+        let failing = results.iter().filter(|result| !result.holds()).collect_vec();
+        assert_eq!(failing, vec![&PropositionResult { name: "gate_0".to_string(), failing_rows: vec![0], undetermined_rows: vec![] }]);
+    }
+}
@@ -1,7 +1,7 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs;
+use std::io::{ErrorKind, Write};
 use std::marker::PhantomData;
-use std::path::Path;
 
 use halo2_frontend::plonk::sealed::SealedPhase;
 use halo2_frontend::plonk::{sealed, Phase};
@@ -14,23 +14,221 @@ use halo2_proofs::{
     plonk::{Advice, Any, Assigned, Assignment, Column, Error, Fixed, FloorPlanner, Instance, Selector},
 };
 
-use crate::field::TermField;
-use crate::utils::{get_group_annotations, group_values, make_lean_comment, print_grouped_props, update_column_annotation, update_row_annotation};
+use crate::analysis::metrics::MetricsOutput;
+use crate::analysis::{queries, split_selector_guards, ColumnKind};
+use crate::cse::{CseMode, TermDag};
+use crate::field::{symbols_in, ExtractionSession, FieldProfile, ReprMode, TermField};
+use crate::printer::{render_tuple, BinaryOp, LeanTerm, DEFAULT_LINE_WIDTH};
+use crate::simplify::{is_identically_zero, simplify};
+use crate::spec::SpecOptions;
+use crate::utils::{
+    annotation_doc_lines, get_group_annotations, group_props, group_values, is_numeral, make_lean_comment, make_lean_docstring, mangle_identifier,
+    print_documented_grouped_props, print_grouped_props, update_column_annotation, update_row_annotation,
+};
+
+pub(crate) const GROUPING_SIZE: usize = 10;
+// Fixed columns with more runs of equal values than this get no unfolding lemmas
+const MAX_UNFOLDING_RUNS: usize = 64;
+// Fixed columns with more runs of equal values than this are emitted as arrays by FixedEncoding::Auto
+const MIN_ARRAY_RUNS: usize = 100;
+// The Finset form of a lookup is only emitted for tables of at most this many rows, as its lemma cases on every row
+const MAX_FINSET_ROWS: usize = 1024;
+
+// How fixed columns are emitted
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FixedEncoding {
+    // Arrays for large numeric columns assigned on a contiguous range of rows, such as lookup tables, and if-chains otherwise
+    #[default]
+    Auto,
+    // Nested if-chains over the runs of equal values, which unfolding lemmas can split on
+    IfChain,
+    // Arrays for every numeric column assigned on a contiguous range of rows
+    Array,
+}
+
+// The field the values of the emitted model are in
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FieldMode {
+    // ZMod P for a prime P
+    #[default]
+    ZMod,
+    // Any finite field F given by Mathlib's classes, so that proofs apply to extension fields too
+    // ZMod P is one instance
+    Generic,
+}
+
+impl FieldMode {
+    // The type every emitted def takes its circuit as
+    pub fn circuit_type(&self) -> &'static str {
+        match self {
+            FieldMode::ZMod => "ValidCircuit P P_Prime",
+            FieldMode::Generic => "ValidCircuit F",
+        }
+    }
+
+    pub fn value_type(&self) -> &'static str {
+        match self {
+            FieldMode::ZMod => "ZMod P",
+            FieldMode::Generic => "F",
+        }
+    }
+
+    // The implicit parameters of the emitted defs, as declared by the preamble
+    pub fn variables(&self) -> &'static str {
+        match self {
+            FieldMode::ZMod => "{P: ℕ} {P_Prime: Nat.Prime P}",
+            FieldMode::Generic => "{F: Type} [Field F] [Fintype F] [DecidableEq F]",
+        }
+    }
+
+    // The parameter that determines the field, for passing it by name
+    pub fn field_param(&self) -> &'static str {
+        match self {
+            FieldMode::ZMod => "P",
+            FieldMode::Generic => "F",
+        }
+    }
+
+    // The parameters of Circuit and ValidCircuit, and the arguments they are applied to
+    fn circuit_params(&self) -> (&'static str, &'static str) {
+        match self {
+            FieldMode::ZMod => ("(P: ℕ) (P_Prime: Nat.Prime P)", "P P_Prime"),
+            FieldMode::Generic => ("(F: Type) [Field F] [Fintype F]", "F"),
+        }
+    }
+
+    // The number of elements of the field
+    fn order(&self) -> &'static str {
+        match self {
+            FieldMode::ZMod => "P",
+            FieldMode::Generic => "(Fintype.card F)",
+        }
+    }
+}
+
+// Settings that apply to a single call to ExtractingAssignment::run_with_options
+#[derive(Clone, Debug)]
+pub struct ExtractionOptions {
+    // Encoding used by PrimeField::to_repr and from_repr while the circuit is configured and synthesized
+    pub repr_mode: ReprMode,
+    // Gate, lookup and shuffle expressions longer than this are broken across lines
+    pub line_width: usize,
+    // Whether and how repeated sub-expressions of gates are bound to names
+    pub cse: CseMode,
+    // Fold constants and remove identity terms before emitting, see simplify::simplify
+    // Disable to audit the expressions exactly as the circuit built them
+    pub simplify: bool,
+    // Report assigned advice cells that no active constraint mentions, see print_unconstrained_advice_cells
+    pub check_unconstrained: bool,
+    // Report the degree and size of each emitted gate polynomial, lookup and shuffle, see report_constraint_metrics
+    pub metrics: MetricsOutput,
+    // The k the circuit is meant to be used with, so that the table has 2^k rows
+    // If None, analyses that depend on it use the smallest k the circuit fits in
+    pub k: Option<u32>,
+    // Report enabled gates whose rotations query blinding rows or wrap around the table, see print_rotation_boundary_violations
+    pub check_rotation_boundaries: bool,
+    // Path to render the recorded layout to as an SVG, see render_layout
+    pub layout_svg: Option<String>,
+    // Emit lemmas stating which pairs of selectors are never on in the same row, see print_selector_disjointness
+    pub selector_lemmas: bool,
+    // Report unused columns, selectors that are never enabled and constraints that can never fire, see print_unused_report
+    pub check_unused: bool,
+    // Emit lemmas characterising selector_func_col_N and fixed_func_col_N, see print_unfolding_lemmas
+    pub unfolding_lemmas: bool,
+    // Write a proof skeleton for the extracted model to a separate file, see spec_skeleton
+    pub spec: Option<SpecOptions>,
+    // Index columns by Fin num_columns rather than ℕ, so that references to columns that do not exist fail to type check
+    pub typed_columns: bool,
+    // Emit gates of the shape s * body as s = 1 → body = 0, with lemmas relating them to the polynomial form
+    // Only applies without CSE, as the let bindings would have to be split between the guard and the body
+    pub guarded_gates: bool,
+    // Whether fixed columns are emitted as if-chains or arrays, see print_fixed_array
+    pub fixed_encoding: FixedEncoding,
+    // Emit lookups into tables of known fixed values as Finset membership too, see print_finset_lookups
+    pub finset_lookups: bool,
+    // Whether the model is over ZMod P or any finite field
+    pub field_mode: FieldMode,
+    // The concrete field the circuit is meant for, whose constants meets_constraints then fixes, see FieldProfile
+    pub field_profile: Option<FieldProfile>,
+}
+
+impl Default for ExtractionOptions {
+    fn default() -> Self {
+        Self {
+            repr_mode: ReprMode::default(),
+            line_width: DEFAULT_LINE_WIDTH,
+            cse: CseMode::default(),
+            simplify: false,
+            check_unconstrained: false,
+            metrics: MetricsOutput::default(),
+            k: None,
+            check_rotation_boundaries: false,
+            layout_svg: None,
+            selector_lemmas: false,
+            check_unused: false,
+            unfolding_lemmas: false,
+            spec: None,
+            typed_columns: false,
+            guarded_gates: false,
+            fixed_encoding: FixedEncoding::default(),
+            finset_lookups: false,
+            field_mode: FieldMode::default(),
+            field_profile: None,
+        }
+    }
+}
+
+// A gate polynomial as it is emitted, after the transformations enabled in the options
+pub struct EmittedGate {
+    pub gate_idx: usize,
+    pub gate_name: String,
+    pub poly_idx: usize,
+    pub num_polys: usize,
+    pub constraint_name: String,
+    pub polynomial: Expression<TermField>,
+    // If the polynomial is trivially true it is not emitted, and this holds the reason to report
+    pub trivial: Option<&'static str>,
+}
 
-const GROUPING_SIZE: usize = 10;
+impl EmittedGate {
+    pub fn description(&self) -> String {
+        format!(
+            "Gate number {} name: \"{}\" part {}/{} {}",
+            self.gate_idx+1,
+            self.gate_name,
+            self.poly_idx+1,
+            self.num_polys,
+            self.constraint_name
+        )
+    }
+}
+
+// The rows a region assigned cells or enabled selectors in, recorded during the first phase
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RegionLayout {
+    pub name: String,
+    // First and last row, inclusive, or None if the region assigned nothing
+    pub rows: Option<(usize, usize)>,
+}
 
 pub struct ExtractingAssignment<F: Field> {
     _marker: PhantomData<F>,
-    advice_column_annotations: BTreeMap<usize, (Option<String>, BTreeMap<usize, String>)>,
+    pub(crate) advice_column_annotations: BTreeMap<usize, (Option<String>, BTreeMap<usize, String>)>,
     current_region: Option<String>,
-    copies: Vec<((Column<Any>, usize), (Column<Any>, usize))>,
-    selectors: BTreeMap<usize, BTreeMap<usize, String>>,
-    fixed: BTreeMap<usize, BTreeMap<usize, String>>,
-    fixed_column_annotations: BTreeMap<usize, (Option<String>, BTreeMap<usize, String>)>,
-    fixed_fill: BTreeMap<usize, (usize, String)>,
-    instance_column_annotations: BTreeMap<usize, (Option<String>, BTreeMap<usize, String>)>,
+    pub(crate) regions: Vec<RegionLayout>,
+    pub(crate) copies: Vec<((Column<Any>, usize), (Column<Any>, usize))>,
+    pub(crate) selectors: BTreeMap<usize, BTreeMap<usize, String>>,
+    pub(crate) fixed: BTreeMap<usize, BTreeMap<usize, String>>,
+    pub(crate) fixed_column_annotations: BTreeMap<usize, (Option<String>, BTreeMap<usize, String>)>,
+    pub(crate) fixed_fill: BTreeMap<usize, (usize, String)>,
+    pub(crate) instance_column_annotations: BTreeMap<usize, (Option<String>, BTreeMap<usize, String>)>,
     current_phase: sealed::Phase,
     usable_rows_filename: String,
+    pub(crate) options: ExtractionOptions,
+    // Comments produced during synthesis, held back until the preamble has been printed
+    synthesis_comments: Vec<String>,
+    // Symbols the recorded values and the constraints refer to, which the Circuit structure declares
+    pub(crate) symbols: Vec<String>,
 }
 
 impl<F: Field> Drop for ExtractingAssignment<F> {
@@ -42,20 +240,28 @@ impl<F: Field> Drop for ExtractingAssignment<F> {
 // impl<F: Field + From<String> + Display> ExtractingAssignment<F> {
 impl ExtractingAssignment<TermField> {
     pub fn new() -> Self {
-        let usable_rows_filename = if Path::new("./usable_rows").exists() {
-            let mut i = 1;
-            while Path::new(&format!("./usable_rows_{i}")).exists() {
-                i += 1;
+        Self::new_with_options(ExtractionOptions::default())
+    }
+
+    pub fn new_with_options(options: ExtractionOptions) -> Self {
+        // Creating the file fails if it exists, so extractions running at the same time never share one
+        let mut i = 0;
+        let usable_rows_filename = loop {
+            let filename = if i == 0 { "usable_rows".to_string() } else { format!("usable_rows_{i}") };
+            match fs::OpenOptions::new().write(true).create_new(true).open(&filename) {
+                Ok(mut file) => {
+                    file.write_all(b"0").expect("Failed to write to usable_rows");
+                    break filename;
+                },
+                Err(err) if err.kind() == ErrorKind::AlreadyExists => i += 1,
+                Err(err) => panic!("Failed to create usable_rows file: {err}"),
             }
-            format!("usable_rows_{i}")
-        } else {
-            "usable_rows".to_string()
         };
-        fs::write(&usable_rows_filename, "0").expect("Failed to write to usable_rows");
         Self {
             _marker: PhantomData,
             advice_column_annotations: BTreeMap::new(),
             current_region: None,
+            regions: vec![],
             copies: vec![],
             selectors: BTreeMap::new(),
             fixed: BTreeMap::new(),
@@ -64,20 +270,30 @@ impl ExtractingAssignment<TermField> {
             instance_column_annotations: BTreeMap::new(),
             current_phase: FirstPhase.to_sealed(),
             usable_rows_filename,
+            options,
+            synthesis_comments: vec![],
+            symbols: vec![],
         }
     }
 
+    // The circuit and value types of the emitted defs
+    pub(crate) fn field_types(&self) -> (&'static str, &'static str) {
+        (self.options.field_mode.circuit_type(), self.options.field_mode.value_type())
+    }
+
     fn in_phase<P: Phase>(&self, phase: P) -> bool {
         self.current_phase == phase.to_sealed()
     }
 
     fn print_copy_constraints(&self) {
+        let (circuit, _) = self.field_types();
 
+        let typed = self.options.typed_columns;
         let format_side = |col: &Column<Any>, row| {
             match col.column_type() {
-                Any::Advice => format!("c.get_advice {} {}", col.index(), row),
-                Any::Fixed => format!("c.get_fixed {} {}", col.index(), row),
-                Any::Instance => format!("c.get_instance {} {}", col.index(), row),
+                Any::Advice => format!("c.get_advice {} {}", column_ref("advice", col.index(), typed), row),
+                Any::Fixed => format!("c.get_fixed {} {}", column_ref("fixed", col.index(), typed), row),
+                Any::Instance => format!("c.get_instance {} {}", column_ref("instance", col.index(), typed), row),
             }
         };
 
@@ -89,34 +305,15 @@ impl ExtractingAssignment<TermField> {
             })
             .collect_vec();
 
-        print_grouped_props("copy_", "all_copy_constraints", &props, GROUPING_SIZE);
+        print_grouped_props("copy_", "all_copy_constraints", circuit, &props, GROUPING_SIZE);
     }
 
-    // TODO grouping, annotations
-    fn print_selectors(&self) {
+    // TODO grouping
+    fn print_selectors(&self, cs: &ConstraintSystem<TermField>) {
+        let (circuit, value) = self.field_types();
         for (col, row_set) in &self.selectors {
-            if let Some((&start, _)) = row_set.first_key_value() {
-                let runs = {
-                    let mut start = start;
-                    // End is inclusive
-                    let mut end = start;
-                    let mut runs = vec![];
-    
-                    // Iterate through the true rows, to collect the consecutive runs
-                    for (&i, _) in row_set.iter().skip(1) {
-                        if end == i - 1 {
-                            // We have found a row that connects to the current run
-                            end = i;
-                        } else {
-                            runs.push((start, end));
-                            start = i;
-                            end = i;
-                        }
-                    }
-    
-                    runs.push((start, end));
-                    runs
-                };
+            if !row_set.is_empty() {
+                let runs = selector_runs(row_set);
 
                 let body = runs
                     .iter()
@@ -128,17 +325,30 @@ impl ExtractingAssignment<TermField> {
                         }
                     })
                     .join("\n  else ");
-                println!("def selector_func_col_{col} (c: ValidCircuit P P_Prime) : ℕ → ZMod P :=");
+                let mut doc = vec![format!("Selector {col}")];
+                doc.extend(annotation_doc_lines(row_set));
+                println!("{}", make_lean_docstring(&doc));
+                println!("def selector_func_col_{col} (c: {circuit}) : ℕ → {value} :=");
                 println!("  λ row =>");
                 println!("  {body}");
                 println!("  else 0");
             } else {
-                println!("def selector_func_col_{col} (c: ValidCircuit P P_Prime) : ℕ → ZMod P :=");
+                println!("def selector_func_col_{col} (c: {circuit}) : ℕ → {value} :=");
                 println!("  λ _ => 0");
             }
 
         }
-        println!("def selector_func (c: ValidCircuit P P_Prime) : ℕ → ℕ → ZMod P :=");
+        if self.options.typed_columns {
+            let entries = (0..cs.num_selectors())
+                .map(|col| {
+                    let entry = if self.selectors.contains_key(&col) { format!("selector_func_col_{col} c row") } else { "0".to_string() };
+                    (entry, None)
+                })
+                .collect_vec();
+            print_fin_table(&format!("selector_func (c: {circuit}) : Fin {} → ℕ → {value}", cs.num_selectors()), "col row", value, &entries);
+            return;
+        }
+        println!("def selector_func (c: {circuit}) : ℕ → ℕ → {value} :=");
         println!("  λ col row => match col with");
         for col in self.selectors.keys() {
             println!("    | {col} => selector_func_col_{col} c row")
@@ -146,14 +356,102 @@ impl ExtractingAssignment<TermField> {
         println!("    | _ => 0");
     }
 
-    fn print_fixed(&self) {
+    // Whether print_fixed emits the column as an array rather than an if-chain
+    pub(crate) fn fixed_is_array(&self, col: usize) -> bool {
+        let Some(row_set) = self.fixed.get(&col) else {
+            return false;
+        };
+        // Arrays can only hold contiguous rows, as the rows in between would be unassigned
+        let contiguous = match (row_set.keys().next(), row_set.keys().last()) {
+            (Some(first), Some(last)) => last - first + 1 == row_set.len(),
+            _ => false,
+        };
+        // The array is a def without a circuit argument, so it can only hold values that do not refer to c
+        let numeric = row_set.values().all(|value| is_numeral(value));
+        contiguous && numeric && match self.options.fixed_encoding {
+            FixedEncoding::Auto => group_values(row_set).len() > MIN_ARRAY_RUNS,
+            FixedEncoding::IfChain => false,
+            FixedEncoding::Array => true,
+        }
+    }
+
+    // Emits a fixed column assigned on a contiguous range of rows as an array of its values, which Lean elaborates
+    // much faster than an if-chain, with lemmas that give its value at every row as the if-chain would
+    fn print_fixed_array(&self, col: usize, row_set: &BTreeMap<usize, String>, doc: &str) {
+        let (circuit, value) = self.field_types();
+        let start = *row_set.keys().next().expect("Fixed columns are recorded when a cell is assigned");
+        let len = row_set.len();
+        let column = column_ref("fixed", col, self.options.typed_columns);
+        let field = self.options.field_mode.field_param();
+        let table = format!("(fixed_table_col_{col} ({field} := {field}))");
+        let (condition, index) = if start == 0 {
+            (format!("row < {len}"), "row".to_string())
+        } else {
+            (format!("row ≥ {start} ∧ row < {}", start + len), format!("(row - {start})"))
+        };
+
+        println!("def fixed_table_col_{col} : Array ({value}) := #[");
+        let mut line = String::new();
+        for (idx, value) in row_set.values().enumerate() {
+            let entry = if idx + 1 == len { value.clone() } else { format!("{value}, ") };
+            if !line.is_empty() && 2 + line.chars().count() + entry.chars().count() > self.options.line_width {
+                println!("  {}", line.trim_end());
+                line.clear();
+            }
+            line.push_str(&entry);
+        }
+        println!("  {line}");
+        println!("]");
+        println!("{doc}");
+        println!("def fixed_func_col_{col} (c: {circuit}) : ℕ → {value} :=");
+        println!("  λ row => if {condition} then {table}.getD {index} 0 else c.1.FixedUnassigned {column} row");
+
+        let (row, proof) = if start == 0 {
+            ("i".to_string(), "exact if_pos h".to_string())
+        } else {
+            (format!("({start} + i)"), "rw [if_pos (by omega), Nat.add_sub_cancel_left]".to_string())
+        };
+        println!("lemma fixed_func_col_{col}_get (c: {circuit}) (i: ℕ) (h: i < {len}) :");
+        println!("  fixed_func_col_{col} c {row} = {table}.getD i 0 := by");
+        println!("  simp only [fixed_func_col_{col}]");
+        println!("  {proof}");
+        println!("lemma fixed_func_col_{col}_unassigned (c: {circuit}) (row: ℕ) (h: ¬({condition})) :");
+        println!("  fixed_func_col_{col} c row = c.1.FixedUnassigned {column} row := by");
+        println!("  simp only [fixed_func_col_{col}]");
+        println!("  exact if_neg h");
+    }
+
+    // Returns the names of the helper defs each if-chain column was split into, which unfolding it requires
+    // Columns emitted as arrays have no entry
+    fn print_fixed(&self, cs: &ConstraintSystem<TermField>) -> BTreeMap<usize, Vec<String>> {
+        let (circuit, value) = self.field_types();
+        let mut helpers = BTreeMap::new();
         for (col, row_set) in &self.fixed {
+            let mut doc = vec![];
+            match self.fixed_column_annotations.get(col) {
+                Some((column_annotation, rows)) => {
+                    doc.push(match column_annotation {
+                        Some(annotation) => format!("Fixed column {col}: {annotation}"),
+                        None => format!("Fixed column {col}"),
+                    });
+                    doc.extend(annotation_doc_lines(rows));
+                },
+                None => doc.push(format!("Fixed column {col}")),
+            }
+            let doc = make_lean_docstring(&doc);
+
+            let mut column_helpers = vec![];
             // (value, start, end, annotations already printed)
             let mut entries = group_values(row_set)
                 .into_iter()
                 .map(|(a,b,c)| (a,b,c,false))
                 .collect_vec();
 
+            if self.fixed_is_array(*col) {
+                self.print_fixed_array(*col, row_set, &doc);
+                continue;
+            }
+
             assert!(GROUPING_SIZE > 1);
 
             while entries.len() > GROUPING_SIZE {
@@ -164,8 +462,9 @@ impl ExtractingAssignment<TermField> {
                         let start = entries[0].1;
                         let end = entries[GROUPING_SIZE-1].2.unwrap_or(entries[GROUPING_SIZE-1].1);
                         let name = format!("fixed_func_col_{col}_{start}_to_{end}");
-                        println!("def {name} (c: ValidCircuit P P_Prime) : ℕ → ZMod P :=");
+                        println!("def {name} (c: {circuit}) : ℕ → {value} :=");
                         println!("  λ row =>");
+                        column_helpers.push(name.clone());
                         new_entries.push((
                             format!("{name} c row"),
                             start,
@@ -214,7 +513,7 @@ impl ExtractingAssignment<TermField> {
                         }
                         entries.remove(0);
                     }
-                    println!("  else c.1.FixedUnassigned {col} row");
+                    println!("  else c.1.FixedUnassigned {} row", column_ref("fixed", *col, self.options.typed_columns));
                 }
 
                 for new_entry in new_entries.into_iter().rev() {
@@ -222,7 +521,8 @@ impl ExtractingAssignment<TermField> {
                 }
             }
 
-            println!("def fixed_func_col_{col} (c: ValidCircuit P P_Prime) : ℕ → ZMod P :=");
+            println!("{doc}");
+            println!("def fixed_func_col_{col} (c: {circuit}) : ℕ → {value} :=");
             println!("  λ row =>");
             let mut first = true;
             for (value, start, end, _) in entries {
@@ -238,10 +538,22 @@ impl ExtractingAssignment<TermField> {
                     println!("if row = {start} then {value}")
                 }
             }
-            println!("  else c.1.FixedUnassigned {col} row");
+            println!("  else c.1.FixedUnassigned {} row", column_ref("fixed", *col, self.options.typed_columns));
+            helpers.insert(*col, column_helpers);
         }
 
-        println!("def fixed_func (c: ValidCircuit P P_Prime) : ℕ → ℕ → ZMod P :=");
+        if self.options.typed_columns {
+            let entries = (0..cs.num_fixed_columns())
+                .map(|col| {
+                    let entry = if self.fixed.contains_key(&col) { format!("fixed_func_col_{col} c row") } else { "c.1.FixedUnassigned col row".to_string() };
+                    let annotation = self.fixed_column_annotations.get(&col).and_then(|(annotation, _)| annotation.clone());
+                    (entry, annotation)
+                })
+                .collect_vec();
+            print_fin_table(&format!("fixed_func (c: {circuit}) : Fin {} → ℕ → {value}", cs.num_fixed_columns()), "col row", value, &entries);
+            return helpers;
+        }
+        println!("def fixed_func (c: {circuit}) : ℕ → ℕ → {value} :=");
         println!("  λ col row => match col with");
         for col in self.fixed.keys() {
             if let Some((Some(annotation), _)) = self.fixed_column_annotations.get(col) {
@@ -251,10 +563,107 @@ impl ExtractingAssignment<TermField> {
             }
         }
         println!("    | _ => c.1.FixedUnassigned col row");
+        helpers
+    }
+
+    // Lemmas characterising selector_func_col_N and the runs of fixed_func_col_N, so proofs need not unfold the if-chains
+    fn print_unfolding_lemmas(&self, fixed_helpers: &BTreeMap<usize, Vec<String>>) {
+        print!("{}", self.unfolding_lemmas(fixed_helpers));
+    }
+
+    // The Lean text print_unfolding_lemmas prints
+    pub(crate) fn unfolding_lemmas(&self, fixed_helpers: &BTreeMap<usize, Vec<String>>) -> String {
+        let mut lines: Vec<String> = vec![];
+        macro_rules! emit {
+            ($($arg:tt)*) => { lines.push(format!($($arg)*)) };
+        }
+        let (circuit, _) = self.field_types();
+        emit!("-- Unfolding lemmas");
+        for (col, row_set) in &self.selectors {
+            let name = format!("selector_func_col_{col}");
+            let condition = if row_set.is_empty() {
+                "False".to_string()
+            } else {
+                selector_runs(row_set)
+                    .iter()
+                    .map(|(start, end)| {
+                        if *start == 0 {
+                            format!("row < {}", end+1)
+                        } else {
+                            format!("(row ≥ {start} ∧ row < {})", end+1)
+                        }
+                    })
+                    .join(" ∨ ")
+            };
+            emit!("lemma {name}_eq_one_iff (c: {circuit}) (row: ℕ) : {name} c row = 1 ↔ {condition} := by");
+            // 0 ≠ 1 in ZMod P needs P to be prime, any other field is nontrivial by definition
+            if self.options.field_mode == FieldMode::ZMod {
+                emit!("  haveI : Fact P.Prime := ⟨P_Prime⟩");
+            }
+            if row_set.is_empty() {
+                emit!("  simp [{name}]");
+            } else {
+                emit!("  simp only [{name}]");
+                emit!("  split_ifs <;> (try simp) <;> omega");
+            }
+        }
+
+        for (col, row_set) in &self.fixed {
+            let runs = group_values(row_set);
+            // One lemma per run, which would swamp the output for long columns
+            if runs.len() > MAX_UNFOLDING_RUNS {
+                emit!("-- fixed_func_col_{col} has {} runs, too many for unfolding lemmas", runs.len());
+                continue;
+            }
+            if !fixed_helpers.contains_key(col) {
+                emit!("-- fixed_func_col_{col} is backed by an array, see fixed_func_col_{col}_get");
+                continue;
+            }
+            self.fixed_branch_lemmas(&mut lines, *col, &runs, &format!("fixed_func_col_{col}"), &fixed_branches(*col, &runs), true);
+        }
+        lines.join("\n") + "\n"
+    }
+
+    // A lemma for each branch of def, in the order of the rows
+    // A helper def gets a lemma equating it to fixed_func_col_N on its rows, which the lemmas of its own branches start from,
+    // so that each proof only steps through the branches of one def
+    fn fixed_branch_lemmas(&self, lines: &mut Vec<String>, col: usize, runs: &[(String, usize, Option<usize>)], def: &str, branches: &[FixedBranch], top: bool) {
+        let (circuit, _) = self.field_types();
+        for (pos, branch) in branches.iter().enumerate() {
+            let (name, rhs) = match branch {
+                FixedBranch::Run { idx, .. } => (format!("fixed_func_col_{col}_run_{idx}"), runs[*idx].0.clone()),
+                FixedBranch::Helper { name, .. } => (format!("{name}_unfold"), format!("{name} c row")),
+            };
+            lines.push(format!(
+                "lemma {name} (c: {circuit}) (row: ℕ) (h_start: row ≥ {}) (h_end: row ≤ {}) :",
+                branch.start(),
+                branch.end()
+            ));
+            lines.push(format!("  fixed_func_col_{col} c row = {rhs} := by"));
+            if !top {
+                lines.push(format!("  rw [{def}_unfold c row (by omega) (by omega)]"));
+            }
+            lines.push(format!("  simp only [{def}]"));
+            let rewrites = branches[..pos]
+                .iter()
+                .map(|skipped| format!("if_neg (show ¬({}) by omega)", skipped.condition()))
+                .chain(std::iter::once(format!("if_pos (show {} by omega)", branch.condition())))
+                .join(", ");
+            lines.push(format!("  rw [{rewrites}]"));
+            if let FixedBranch::Helper { name, branches, .. } = branch {
+                self.fixed_branch_lemmas(lines, col, runs, name, branches, false);
+            }
+        }
     }
 
     fn print_advice_phase(&self, cs: &ConstraintSystem<TermField>) {
-        println!("def advice_phase (c: ValidCircuit P P_Prime) : ℕ → ℕ :=");
+        let (circuit, _) = self.field_types();
+        if self.options.typed_columns {
+            let entries = cs.advice_column_phase().iter().map(|phase| (phase.to_string(), None)).collect_vec();
+            print_fin_table(&format!("advice_phase (c: {circuit}) : Fin {} → ℕ", cs.num_advice_columns()), "col", "ℕ", &entries);
+            return;
+        }
+        println!("def advice_phase (c: {circuit}) : ℕ → ℕ :=");
         println!("  λ col => match col with");
         for (col, phase) in cs.advice_column_phase().iter().enumerate() {
             if *phase != 0 {
@@ -264,95 +673,295 @@ impl ExtractingAssignment<TermField> {
         println!("  | _ => 0");
     }
 
-    fn print_advice_annotations(&self) {
-        println!("  -- Advice column annotations:");
-        if self.advice_column_annotations.is_empty() {
-            println!("  -- None");
+    // Emits, for each kind of column, a table from column to its annotation,
+    // and an abbreviation for each annotated column documented with its annotations, such as col_fib_a
+    fn print_column_names(&self, cs: &ConstraintSystem<TermField>) {
+        print!("{}", self.column_names(cs));
+    }
+
+    // The Lean text print_column_names prints
+    pub(crate) fn column_names(&self, cs: &ConstraintSystem<TermField>) -> String {
+        let mut lines: Vec<String> = vec![];
+        macro_rules! emit {
+            ($($arg:tt)*) => { lines.push(format!($($arg)*)) };
         }
-        self.advice_column_annotations
-            .iter()
-            .for_each(|(col, (column_annotation, rows))| {
-                println!("-- Advice Column {col}");
-                if let Some(column_annotation) = column_annotation {
-                    println!("{}", make_lean_comment(column_annotation));
-                }
-                if let Some((start, _)) = rows.first_key_value() {
-                    if let Some ((end, _)) = rows.last_key_value() {
-                        if let Some(comments) = get_group_annotations(rows, *start, *end) {
-                            println!("{comments}");
-                        }
+        let typed = self.options.typed_columns;
+        let mut abbreviation_names: Vec<String> = vec![];
+        for (kind, count, annotations) in [
+            ("advice", cs.num_advice_columns(), &self.advice_column_annotations),
+            ("fixed", cs.num_fixed_columns(), &self.fixed_column_annotations),
+            ("instance", cs.num_instance_columns(), &self.instance_column_annotations),
+        ] {
+            let name_of = |col: &usize| annotations.get(col).and_then(|(annotation, _)| annotation.clone());
+            if typed {
+                let entries = (0..count)
+                    .map(|col| (format!("\"{}\"", escape_lean_string(&name_of(&col).unwrap_or_default())), None))
+                    .collect_vec();
+                lines.push(fin_table(&format!("{kind}_column_name : Fin {count} → String"), "col", "String", &entries).trim_end().to_string());
+            } else {
+                emit!("def {kind}_column_name : ℕ → String");
+                for col in annotations.keys() {
+                    if let Some(name) = name_of(col) {
+                        emit!("  | {col} => \"{}\"", escape_lean_string(&name));
                     }
                 }
-            });
-    }
+                emit!("  | _ => \"\"");
+            }
 
-    fn print_instance_annotations(&self) {
-        println!("  -- Instance column annotations:");
-        if self.instance_column_annotations.is_empty() {
-            println!("  -- None");
-        }
-        self.instance_column_annotations
-            .iter()
-            .for_each(|(col, (column_annotation, rows))| {
-                println!("-- Instance Column {col}");
-                if let Some(column_annotation) = column_annotation {
-                    println!("{}", make_lean_comment(column_annotation));
+            for (col, (column_annotation, rows)) in annotations.iter().filter(|(col, _)| **col < count) {
+                let mut name = format!("col_{}", column_annotation.as_deref().map_or(format!("{kind}_{col}"), mangle_identifier));
+                // The suffix can itself collide, with an annotation that happens to end in it
+                while abbreviation_names.contains(&name) {
+                    name = format!("{name}_{kind}_{col}");
                 }
-                if let Some((start, _)) = rows.first_key_value() {
-                    if let Some ((end, _)) = rows.last_key_value() {
-                        if let Some(comments) = get_group_annotations(rows, *start, *end) {
-                            println!("{comments}");
-                        }
-                    }
+                abbreviation_names.push(name.clone());
+
+                let mut doc = vec![match column_annotation {
+                    Some(annotation) => format!("{kind} column {col}: {annotation}"),
+                    None => format!("{kind} column {col}"),
+                }];
+                doc.extend(annotation_doc_lines(rows));
+                emit!("{}", make_lean_docstring(&doc));
+                if typed {
+                    emit!("abbrev {name} : Fin {count} := {}", column_ref(kind, *col, true));
+                } else {
+                    emit!("abbrev {name} : ℕ := {col}");
                 }
-            });
+            }
+        }
+        lines.join("\n") + "\n"
     }
 
-    fn print_gates(&self, cs: &ConstraintSystem<TermField>) {
-        let constraints = cs
+    pub(crate) fn expression_to_term(&self, expr: &Expression<TermField>, row_name: &str) -> LeanTerm {
+        expression_to_term_with_columns(expr, row_name, self.options.typed_columns)
+    }
+
+    // Applies the transformations enabled in the options to an expression before it is emitted
+    pub(crate) fn prepare_expression(&self, expr: &Expression<TermField>) -> Expression<TermField> {
+        if self.options.simplify {
+            simplify(expr)
+        } else {
+            expr.clone()
+        }
+    }
+
+    // Every gate polynomial in the order it is emitted, including those that are not emitted because they are trivially true
+    // The emitted gate_N are the non-trivial entries, numbered in this order
+    pub fn emitted_gates(&self, cs: &ConstraintSystem<TermField>) -> Vec<EmittedGate> {
+        cs
             .gates()
             .iter()
             .enumerate()
-            .flat_map(|(gate_idx, gate)| {
+            .flat_map(move |(gate_idx, gate)| {
                 // Each gate can contain many polynomials, so we need an inner iteration
                 gate
                     .polynomials()
                     .iter()
                     .enumerate()
-                    .filter(move |(poly_idx, polynomial)| {
-                        match polynomial {
-                            Expression::Constant(TermField::Val(0)) => {
-                                println!(
-                                    "  -- Gate number {} name: \"{}\" part {}/{} {} is trivially true",
-                                    gate_idx+1,
-                                    gate.name(),
-                                    poly_idx+1,
-                                    gate.polynomials().len(),
-                                    gate.constraint_name(*poly_idx)
-                                );
-                                false
-                            },
-                            _ => true
-                        }
-                    })
                     .map(move |(poly_idx, polynomial)| {
-                        format!(
-                            "-- Gate number {} name: \"{}\" part {}/{} {}\n  ∀ row: ℕ, {} = 0",
-                            gate_idx+1,
-                            gate.name(),
-                            poly_idx+1,
-                            gate.polynomials().len(),
-                            gate.constraint_name(poly_idx),
-                            expression_to_value_string(polynomial, "row")
-                        )
+                        let polynomial = self.prepare_expression(polynomial);
+                        let trivial = match polynomial {
+                            Expression::Constant(TermField::Val(0)) => Some(""),
+                            _ if self.options.simplify && is_identically_zero(&polynomial) => Some(" (identically zero after simplification)"),
+                            _ => None,
+                        };
+                        EmittedGate {
+                            gate_idx,
+                            gate_name: gate.name().to_string(),
+                            poly_idx,
+                            num_polys: gate.polynomials().len(),
+                            constraint_name: gate.constraint_name(poly_idx).to_string(),
+                            polynomial,
+                            trivial,
+                        }
                     })
             })
+            .collect_vec()
+    }
+
+    fn print_gates(&self, cs: &ConstraintSystem<TermField>) {
+        let (circuit, value) = self.field_types();
+        let line_width = self.options.line_width;
+        let gates = self.emitted_gates(cs);
+        for gate in &gates {
+            if let Some(reason) = gate.trivial {
+                println!("  -- {} is trivially true{reason}", gate.description());
+            }
+        }
+        // Defs already taken, which the name derived defs must not clash with
+        let mut reserved: HashSet<String> = group_props("gate_", gates.iter().filter(|gate| gate.trivial.is_none()).count(), GROUPING_SIZE)
+            .0
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        // (description, polynomial)
+        let polynomials = gates
+            .iter()
+            .filter(|gate| gate.trivial.is_none())
+            .map(|gate| (gate.description(), self.expression_to_term(&gate.polynomial, "row")))
             .collect_vec();
 
-        print_grouped_props("gate_", "all_gates", &constraints, GROUPING_SIZE);
+        // (gate number, selector columns, polynomial form) of the gates emitted as implications
+        let mut guarded = vec![];
+        if self.options.guarded_gates && self.options.cse != CseMode::Off {
+            println!("--WARNING: guarded_gates only applies without CSE, so the gates are emitted as polynomials");
+            eprintln!("WARNING: guarded_gates only applies without CSE, so the gates are emitted as polynomials");
+        }
+        let constraints = match self.options.cse {
+            CseMode::Off => {
+                let mut constraints = vec![];
+                let gate_polynomials = gates.iter().filter(|gate| gate.trivial.is_none()).zip(&polynomials);
+                for (idx, (gate, (description, polynomial))) in gate_polynomials.enumerate() {
+                    let raw = format_gate_polynomial(polynomial, line_width);
+                    match split_selector_guards(&gate.polynomial).filter(|_| self.options.guarded_gates) {
+                        Some((selectors, body)) => {
+                            let selectors = selectors
+                                .iter()
+                                .map(|selector| column_ref("selector", *selector, self.options.typed_columns))
+                                .collect_vec();
+                            let formatted = format_guarded_gate(&selectors, &self.expression_to_term(&body, "row"), line_width);
+                            constraints.push((Some(description.clone()), formatted));
+                            guarded.push((idx, selectors, raw));
+                        },
+                        None => constraints.push((Some(description.clone()), raw)),
+                    }
+                }
+                constraints
+            },
+            CseMode::Let => polynomials
+                .iter()
+                .map(|(description, polynomial)| {
+                    let mut dag = TermDag::default();
+                    let root = dag.insert(polynomial);
+                    let shared = dag.shared();
+                    let names = shared
+                        .iter()
+                        .enumerate()
+                        .map(|(idx, id)| (*id, format!("cse_{idx}")))
+                        .collect::<HashMap<_, _>>();
+                    (Some(description.clone()), format_gate_with_lets(&dag, root, &shared, &names, value, line_width))
+                })
+                .collect_vec(),
+            CseMode::Def => {
+                // All gates use the same row variable, so sub-expressions can be shared between them
+                let mut dag = TermDag::default();
+                let roots = polynomials
+                    .iter()
+                    .map(|(_, polynomial)| dag.insert(polynomial))
+                    .collect_vec();
+                let shared = dag.shared();
+                let names = shared
+                    .iter()
+                    .enumerate()
+                    .map(|(idx, id)| (*id, format!("gate_subexpr_{idx} c row")))
+                    .collect::<HashMap<_, _>>();
+                for (idx, id) in shared.iter().enumerate() {
+                    reserved.insert(format!("gate_subexpr_{idx}"));
+                    println!("def gate_subexpr_{idx} (c: {circuit}) (row: ℕ) : {value} :=");
+                    println!("  {}", dag.to_term(*id, &names).render(2, line_width));
+                }
+                polynomials
+                    .iter()
+                    .zip(roots)
+                    .map(|((description, _), root)| {
+                        (Some(description.clone()), format_gate_polynomial(&dag.to_term(root, &names), line_width))
+                    })
+                    .collect_vec()
+            },
+        };
+
+        print_documented_grouped_props("gate_", "all_gates", circuit, &constraints, GROUPING_SIZE);
+
+        // Both views of the guarded gates agree whenever every selector is 0 or 1, which selector_binary derives from meets_constraints
+        if !guarded.is_empty() {
+            self.print_selector_binary();
+        }
+        for (idx, selectors, raw) in &guarded {
+            println!("def gate_{idx}_raw (c: {circuit}) : Prop :=");
+            println!("  {raw}");
+            println!("lemma gate_{idx}_iff_raw (c: {circuit}) (h_selector: ∀ col row, c.get_selector col row = 0 ∨ c.get_selector col row = 1) :");
+            println!("  gate_{idx} c ↔ gate_{idx}_raw c := by");
+            // 0 ≠ 1 in ZMod P needs P to be prime, any other field is nontrivial by definition
+            if self.options.field_mode == FieldMode::ZMod {
+                println!("  haveI : Fact P.Prime := ⟨P_Prime⟩");
+            }
+            println!("  unfold gate_{idx} gate_{idx}_raw");
+            println!("  refine forall_congr' (λ row => ?_)");
+            let cases = selectors
+                .iter()
+                .enumerate()
+                .map(|(case, selector)| format!("rcases h_selector {selector} row with h_{case} | h_{case}"))
+                .join(" <;> ");
+            let hypotheses = (0..selectors.len()).map(|case| format!("h_{case}")).join(", ");
+            println!("  {cases} <;> simp [{hypotheses}]");
+        }
+
+        // Every polynomial of the gates with each name, trivial ones included so that every gate gets a def
+        let mut named: Vec<(String, Vec<String>)> = vec![];
+        let mut idx = 0;
+        for gate in &gates {
+            let position = match named.iter().position(|(name, _)| *name == gate.gate_name) {
+                Some(position) => position,
+                None => {
+                    named.push((gate.gate_name.clone(), vec![]));
+                    named.len() - 1
+                },
+            };
+            if gate.trivial.is_none() {
+                named[position].1.push(format!("gate_{idx}"));
+                idx += 1;
+            }
+        }
+        print_named_props("gate_", circuit, &named, &reserved);
     }
 
-    fn print_lookups(&self, cs: &ConstraintSystem<TermField>) {
+    // Emits selector_binary, that every selector is 0 or 1 given the selector conjunct of meets_constraints
+    fn print_selector_binary(&self) {
+        print!("{}", self.selector_binary());
+    }
+
+    // The Lean text print_selector_binary prints
+    pub(crate) fn selector_binary(&self) -> String {
+        let mut lines: Vec<String> = vec![];
+        macro_rules! emit {
+            ($($arg:tt)*) => { lines.push(format!($($arg)*)) };
+        }
+        let (circuit, _) = self.field_types();
+        for (col, row_set) in &self.selectors {
+            emit!("lemma selector_func_col_{col}_binary (c: {circuit}) (row: ℕ) :");
+            emit!("  selector_func_col_{col} c row = 0 ∨ selector_func_col_{col} c row = 1 := by");
+            if row_set.is_empty() {
+                emit!("  simp [selector_func_col_{col}]");
+            } else {
+                emit!("  simp only [selector_func_col_{col}]");
+                emit!("  split_ifs <;> simp");
+            }
+        }
+        let cases = self
+            .selectors
+            .keys()
+            .map(|col| format!("exact selector_func_col_{col}_binary c row"))
+            .chain(std::iter::once("simp".to_string()))
+            .join(" | ");
+        emit!("lemma selector_binary (c: {circuit}) (h_selector: c.1.Selector = selector_func c) :");
+        emit!("  ∀ col row, c.get_selector col row = 0 ∨ c.get_selector col row = 1 := by");
+        emit!("  intro col row");
+        emit!("  simp only [ValidCircuit.get_selector, h_selector, selector_func]");
+        if self.options.typed_columns {
+            // Every entry of the vector is either a selector_func_col_N or 0
+            let binaries = self.selectors.keys().map(|col| format!("selector_func_col_{col}_binary")).join(", ");
+            emit!("  fin_cases col <;> simp [{binaries}]");
+        } else if !self.selectors.is_empty() {
+            emit!("  split <;> first | {cases}");
+        } else {
+            // selector_func only has the catch-all case when no selector is ever enabled, so there is nothing to split
+            emit!("  simp");
+        }
+        lines.join("\n") + "\n"
+    }
+
+    fn print_lookups(&self, cs: &ConstraintSystem<TermField>, fixed_helpers: &BTreeMap<usize, Vec<String>>) {
+        let (circuit, _) = self.field_types();
         let lookups = cs
             .lookups()
             .iter()
@@ -360,59 +969,203 @@ impl ExtractingAssignment<TermField> {
             .map(|(idx, lookup)| {
                 let lhs = lookup.input_expressions()
                     .iter()
-                    .map(|expr| {
-                        expression_to_value_string(expr, "row")
-                    })
-                    .join(", ");
+                    .map(|expr| self.expression_to_term(&self.prepare_expression(expr), "row"))
+                    .collect_vec();
                 let rhs = lookup.table_expressions()
                     .iter()
-                    .map(|expr| {
-                        expression_to_value_string(expr, "lookup_row")
-                    })
-                    .join(", ");
-                format!(
-                    "∀ row : ℕ, row < c.usable_rows → ∃ lookup_row : ℕ, lookup_row < c.usable_rows ∧ -- Lookup number {} name: \"{}\"\n  ({lhs}) = ({rhs})\n  ",
-                    idx+1,
-                    lookup.name()
+                    .map(|expr| self.expression_to_term(&self.prepare_expression(expr), "lookup_row"))
+                    .collect_vec();
+                let equation = format_tuple_equation(&lhs, &rhs, 2, self.options.line_width);
+                (
+                    Some(format!("Lookup number {} name: \"{}\"", idx+1, lookup.name())),
+                    format!("∀ row : ℕ, row < c.usable_rows → ∃ lookup_row : ℕ, lookup_row < c.usable_rows ∧\n  {equation}\n  ")
                 )
             })
             .collect_vec();
 
-        print_grouped_props("lookup_", "all_lookups", &lookups, GROUPING_SIZE);
+        print_documented_grouped_props("lookup_", "all_lookups", circuit, &lookups, GROUPING_SIZE);
+
+        let mut named: Vec<(String, Vec<String>)> = vec![];
+        for (idx, lookup) in cs.lookups().iter().enumerate() {
+            match named.iter_mut().find(|(name, _)| name == lookup.name()) {
+                Some((_, members)) => members.push(format!("lookup_{idx}")),
+                None => named.push((lookup.name().to_string(), vec![format!("lookup_{idx}")])),
+            }
+        }
+        let mut reserved: HashSet<String> = group_props("lookup_", cs.lookups().len(), GROUPING_SIZE).0.into_iter().map(|(name, _)| name).collect();
+        let tables = if self.options.finset_lookups {
+            self.finset_lookup_tables(cs, fixed_helpers)
+        } else {
+            vec![]
+        };
+        for (idx, _) in &tables {
+            reserved.extend([
+                format!("lookup_table_{idx}"),
+                format!("lookup_{idx}_mem"),
+                format!("lookup_{idx}_of_mem"),
+                format!("lookup_{idx}_iff_mem"),
+            ]);
+        }
+        print_named_props("lookup_", circuit, &named, &reserved);
+        self.print_finset_lookups(cs, fixed_helpers, &tables);
+    }
+
+    // A cell of a lookup table as a Lean term, if the table expression only refers to numeric fixed cells at the lookup row
+    // Unassigned cells are left arbitrary by the model, so they are not known,
+    // and symbolic values refer to c, which the Finset def does not take
+    fn fixed_table_entry(&self, expr: &Expression<TermField>, row: usize) -> Option<LeanTerm> {
+        let numeral = |value: String| is_numeral(&value).then(|| LeanTerm::text(value));
+        match expr {
+            Expression::Constant(value) => numeral(value.to_string()),
+            Expression::Fixed(query) if query.rotation().0 == 0 => {
+                numeral(self.fixed.get(&query.column_index())?.get(&row)?.clone())
+            },
+            Expression::Negated(inner) => Some(LeanTerm::neg(self.fixed_table_entry(inner, row)?)),
+            Expression::Sum(lhs, rhs) =>
+                Some(LeanTerm::binary(BinaryOp::Add, self.fixed_table_entry(lhs, row)?, self.fixed_table_entry(rhs, row)?)),
+            Expression::Product(lhs, rhs) =>
+                Some(LeanTerm::binary(BinaryOp::Mul, self.fixed_table_entry(lhs, row)?, self.fixed_table_entry(rhs, row)?)),
+            Expression::Scaled(inner, factor) =>
+                Some(LeanTerm::binary(BinaryOp::Mul, numeral(factor.to_string())?, self.fixed_table_entry(inner, row)?)),
+            _ => None,
+        }
+    }
+
+    // For each lookup whose table is known at every usable row, the distinct rows of the table with a row each occurs at
+    // Tables that use array backed fixed columns are left out, as the lemmas unfold the if-chains
+    fn finset_lookup_tables(&self, cs: &ConstraintSystem<TermField>, fixed_helpers: &BTreeMap<usize, Vec<String>>) -> Vec<(usize, Vec<(String, usize)>)> {
+        let usable_rows = self.usable_rows();
+        if usable_rows == 0 || usable_rows > MAX_FINSET_ROWS {
+            return vec![];
+        }
+        cs.lookups()
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, lookup)| {
+                let table = lookup.table_expressions().iter().map(|expr| self.prepare_expression(expr)).collect_vec();
+                let uses_array = table
+                    .iter()
+                    .flat_map(queries)
+                    .any(|query| query.kind == ColumnKind::Fixed && !fixed_helpers.contains_key(&query.column));
+                if uses_array {
+                    return None;
+                }
+                let mut entries: Vec<(String, usize)> = vec![];
+                for row in 0..usable_rows {
+                    let entry = table.iter().map(|expr| self.fixed_table_entry(expr, row)).collect::<Option<Vec<_>>>()?;
+                    let entry = render_tuple(&entry, 2, self.options.line_width);
+                    if !entries.iter().any(|(existing, _)| *existing == entry) {
+                        entries.push((entry, row));
+                    }
+                }
+                Some((idx, entries))
+            })
+            .collect_vec()
+    }
+
+    // Emits lookup_N_mem, which states lookup_N as membership in a Finset of the known rows of its table,
+    // so that facts such as x < 256 can be decided, with lemmas that membership implies the lookup, and that the two agree
+    // when the table has exactly the recorded rows
+    fn print_finset_lookups(&self, cs: &ConstraintSystem<TermField>, fixed_helpers: &BTreeMap<usize, Vec<String>>, tables: &[(usize, Vec<(String, usize)>)]) {
+        let (circuit, value) = self.field_types();
+        let usable_rows = self.usable_rows();
+        for (idx, entries) in tables {
+            let lookup = &cs.lookups()[*idx];
+            let lhs = lookup.input_expressions()
+                .iter()
+                .map(|expr| self.expression_to_term(&self.prepare_expression(expr), "row"))
+                .collect_vec();
+            let element_type = vec![value; lookup.table_expressions().len()].join(" × ");
+            let unfold = std::iter::once("fixed_func".to_string())
+                .chain(
+                    lookup
+                        .table_expressions()
+                        .iter()
+                        .flat_map(queries)
+                        .filter(|query| query.kind == ColumnKind::Fixed)
+                        .map(|query| query.column)
+                        .unique()
+                        .flat_map(|col| std::iter::once(format!("fixed_func_col_{col}")).chain(fixed_helpers[&col].iter().cloned())),
+                )
+                .join(", ");
+
+            println!("def lookup_table_{idx} : Finset ({element_type}) := {{");
+            println!("  {}", entries.iter().map(|(entry, _)| entry).join(",\n  "));
+            println!("}}");
+            println!("def lookup_{idx}_mem (c: {circuit}) : Prop :=");
+            println!("  ∀ row : ℕ, row < c.usable_rows → {} ∈ lookup_table_{idx}", render_tuple(&lhs, 2, self.options.line_width));
+
+            // Each element of the table is witnessed by a row it occurs at, which is usable as long as the recorded rows are
+            println!("lemma lookup_{idx}_of_mem (c: {circuit}) (h_fixed: c.1.Fixed = fixed_func c) (h_rows: c.usable_rows ≥ {usable_rows}) :");
+            println!("  lookup_{idx}_mem c → lookup_{idx} c := by");
+            println!("  unfold lookup_{idx} lookup_{idx}_mem");
+            println!("  intro h_mem row h_row");
+            println!("  have h := h_mem row h_row");
+            println!("  simp only [ValidCircuit.get_fixed, h_fixed]");
+            println!("  simp only [lookup_table_{idx}, Finset.mem_insert, Finset.mem_singleton] at h");
+            let witnesses = entries
+                .iter()
+                .map(|(_, row)| format!("exact ⟨{row}, by omega, by rw [h]; simp [{unfold}]⟩"))
+                .collect_vec();
+            if let [witness] = witnesses.as_slice() {
+                println!("  {witness}");
+            } else {
+                println!("  rcases h with {} <;> [", vec!["h"; witnesses.len()].join(" | "));
+                println!("    {}]", witnesses.join(";\n    "));
+            }
+
+            // The converse needs the exact count, as a usable row beyond the recorded ones is unassigned,
+            // so the lookup could match an arbitrary value there that is not in the Finset
+            println!("lemma lookup_{idx}_iff_mem (c: {circuit}) (h_fixed: c.1.Fixed = fixed_func c) (h_rows: c.usable_rows = {usable_rows}) :");
+            println!("  lookup_{idx} c ↔ lookup_{idx}_mem c := by");
+            println!("  refine ⟨?_, lookup_{idx}_of_mem c h_fixed (by omega)⟩");
+            println!("  unfold lookup_{idx} lookup_{idx}_mem");
+            println!("  rw [h_rows]");
+            println!("  intro h_lookup row h_row");
+            println!("  obtain ⟨lookup_row, h_lookup_row, h_eq⟩ := h_lookup row h_row");
+            println!("  simp only [ValidCircuit.get_fixed, h_fixed] at h_eq");
+            println!("  rw [h_eq]");
+            println!("  interval_cases lookup_row <;> simp [lookup_table_{idx}, {unfold}]");
+        }
     }
 
     pub fn print_grouping_props(&self, cs: &ConstraintSystem<TermField>) {
+        let (circuit, _) = self.field_types();
         println!("");
         println!("");
         self.print_copy_constraints();
-        self.print_selectors();
-        self.print_fixed();
+        self.print_selectors(cs);
+        let fixed_helpers = self.print_fixed(cs);
+        if self.options.unfolding_lemmas {
+            self.print_unfolding_lemmas(&fixed_helpers);
+        }
         self.print_advice_phase(&cs);
-        self.print_advice_annotations();
-        self.print_instance_annotations();
+        self.print_column_names(cs);
         self.print_gates(&cs);
-        self.print_lookups(&cs);
+        self.print_lookups(&cs, &fixed_helpers);
         
 
         // Shuffles
         {
             let mut shuffle_names = vec![];
             for shuffle in cs.shuffles() {
-                let name = format!("shuffle_{}", shuffle.name().replace("_", "__").replace(" ", "_")); // TODO mangle if necessary
+                let name = shuffle_name(shuffle.name());
                 shuffle_names.push(name.clone());
                 let lhs = shuffle.input_expressions()
                     .iter()
-                    .map(|expr| {
-                        expression_to_value_string(expr, "row")
-                    })
-                    .join(", ");
+                    .map(|expr| self.expression_to_term(&self.prepare_expression(expr), "row"))
+                    .collect_vec();
                 let rhs = shuffle.shuffle_expressions()
                     .iter()
-                    .map(|expr| {
-                        expression_to_value_string(expr, "(shuffle row)")
-                    })
-                    .join(", ");
-                println!("def {name} (c: ValidCircuit P P_Prime): Prop := ∃ shuffle, is_shuffle c shuffle ∧ (∀ row : ℕ, row < c.usable_rows → ({lhs}) = ({rhs}))");
+                    .map(|expr| self.expression_to_term(&self.prepare_expression(expr), "(shuffle row)"))
+                    .collect_vec();
+                let header = format!("def {name} (c: {circuit}): Prop := ∃ shuffle, is_shuffle c shuffle ∧ (∀ row : ℕ, row < c.usable_rows →");
+                let equation = format_tuple_equation(&lhs, &rhs, 2, self.options.line_width);
+                if equation.contains('\n') || header.chars().count() + equation.chars().count() + 2 > self.options.line_width {
+                    println!("{header}\n  {equation})");
+                } else {
+                    println!("{header} {equation})");
+                }
             }
     
             let all_shuffles_body = if shuffle_names.is_empty() {
@@ -423,7 +1176,7 @@ impl ExtractingAssignment<TermField> {
                     .map(|name| format!("{name} c"))
                     .join(" ∧ ")
             };
-            println!("def all_shuffles (c: ValidCircuit P P_Prime) : Prop := {all_shuffles_body}");
+            println!("def all_shuffles (c: {circuit}) : Prop := {all_shuffles_body}");
         }
     }
 
@@ -487,13 +1240,114 @@ impl ExtractingAssignment<TermField> {
         namespace: &str,
         symbol_names: &[&str]
     ) -> Result<(), Error> {
-        
+        Self::run_with_options(circuit, namespace, symbol_names, &ExtractionOptions::default())
+    }
+
+    pub fn run_with_options<ConcreteCircuit: Circuit<TermField>>(
+        circuit: &ConcreteCircuit,
+        namespace: &str,
+        symbol_names: &[&str],
+        options: &ExtractionOptions,
+    ) -> Result<(), Error> {
+        let (cs, prover) = Self::synthesize_circuit(circuit, options)?;
+
+        // Symbols can be created while the circuit is built, configured or synthesized,
+        // so the preamble declaring them can only be printed once synthesis is done
+        let mut symbols = symbol_names.iter().map(|name| name.to_string()).collect_vec();
+        for symbol in &prover.symbols {
+            if !symbols.contains(symbol) {
+                symbols.push(symbol.clone());
+            }
+        }
+        print_preamble_with_options(namespace, &symbols.iter().map(String::as_str).collect_vec(), &cs, options);
+        for comment in &prover.synthesis_comments {
+            println!("{comment}");
+        }
+
+        prover.print_grouping_props(&cs);
+        if options.selector_lemmas {
+            prover.print_selector_disjointness();
+        }
+
+        if options.check_unconstrained {
+            prover.print_unconstrained_advice_cells(&cs);
+        }
+        if options.check_unused {
+            prover.print_unused_report(&cs);
+        }
+        prover.report_constraint_metrics(&cs, &options.metrics);
+        if options.check_rotation_boundaries {
+            let k = options.k.unwrap_or_else(|| prover.min_k(&cs));
+            prover.print_rotation_boundary_violations(&cs, k);
+        }
+        if let Some(path) = &options.layout_svg {
+            let k = options.k.unwrap_or_else(|| prover.min_k(&cs));
+            if let Err(err) = prover.render_layout_svg(&cs, k, path) {
+                eprintln!("WARNING: Failed to render the layout to {path}: {err}");
+            }
+        }
+
+        prover.print_min_k(&cs);
+        print_postamble_with_options(namespace, &cs, prover.usable_rows(), options);
+        if let Some(spec) = &options.spec {
+            prover.write_spec(&cs, namespace, spec);
+        }
+        Ok(())
+
+    }
+
+    // Configures and synthesizes the circuit, recording its layout without printing anything
+    pub fn synthesize_circuit<ConcreteCircuit: Circuit<TermField>>(
+        circuit: &ConcreteCircuit,
+        options: &ExtractionOptions,
+    ) -> Result<(ConstraintSystem<TermField>, Self), Error> {
+        let session = ExtractionSession::begin(options.repr_mode);
+        let (cs, mut prover) = Self::record_layout(circuit, options)?;
+        drop(session);
+        prover.symbols = prover.referenced_symbols(&cs);
+        Ok((cs, prover))
+    }
+
+    // The symbols the fixed values and the constants of the constraints refer to, in order of first appearance
+    // Symbols that were created but never reached a term are left out, as the model has nothing to declare them for
+    fn referenced_symbols(&self, cs: &ConstraintSystem<TermField>) -> Vec<String> {
+        fn constants(expr: &Expression<TermField>, res: &mut Vec<String>) {
+            match expr {
+                Expression::Constant(value) => res.push(value.to_string()),
+                Expression::Scaled(inner, factor) => {
+                    res.push(factor.to_string());
+                    constants(inner, res);
+                },
+                Expression::Negated(inner) => constants(inner, res),
+                Expression::Sum(lhs, rhs) | Expression::Product(lhs, rhs) => {
+                    constants(lhs, res);
+                    constants(rhs, res);
+                },
+                _ => {},
+            }
+        }
+        let mut texts = self.fixed.values().flat_map(|column| column.values().cloned()).collect_vec();
+        texts.extend(self.fixed_fill.values().map(|(_, value)| value.clone()));
+        let exprs = cs.gates()
+            .iter()
+            .flat_map(|gate| gate.polynomials())
+            .chain(cs.lookups().iter().flat_map(|lookup| lookup.input_expressions().iter().chain(lookup.table_expressions())))
+            .chain(cs.shuffles().iter().flat_map(|shuffle| shuffle.input_expressions().iter().chain(shuffle.shuffle_expressions())));
+        for expr in exprs {
+            constants(expr, &mut texts);
+        }
+        texts.iter().flat_map(|text| symbols_in(text)).unique().map(str::to_string).collect()
+    }
+
+    fn record_layout<ConcreteCircuit: Circuit<TermField>>(
+        circuit: &ConcreteCircuit,
+        options: &ExtractionOptions,
+    ) -> Result<(ConstraintSystem<TermField>, Self), Error> {
         let mut cs = ConstraintSystem::default();
         let config = ConcreteCircuit::configure_with_params(&mut cs, circuit.params());
         let cs = cs;
-        print_preamble(namespace, symbol_names, &cs);
 
-        let mut prover = ExtractingAssignment::new();
+        let mut prover = ExtractingAssignment::new_with_options(options.clone());
 
         for current_phase in cs.phases() {
             prover.current_phase = current_phase;
@@ -505,18 +1359,98 @@ impl ExtractingAssignment<TermField> {
             )?;
         }
 
-        prover.print_grouping_props(&cs);
+        Ok((cs, prover))
+    }
 
-        print_postamble(namespace, &cs);
-        Ok(())
+    // The number of rows the circuit has used so far
+    pub fn usable_rows(&self) -> usize {
+        str::parse::<usize>(
+            &fs::read_to_string(&self.usable_rows_filename)
+                .expect("Failed to read usable_rows file")
+        ).expect("Failed to parse contents of usable_rows file")
+    }
 
+    // Whether the circuit fits in a table of 2^k rows
+    // This mirrors the checks behind MockProver's NotEnoughRowsAvailable: the table needs at least cs.minimum_rows() rows,
+    // and every used row must come before the blinding rows
+    // A k too large for 2^k to be a usize does not fit, as no table that large can be built
+    pub fn fits_k(&self, cs: &ConstraintSystem<TermField>, k: u32) -> bool {
+        let Some(n) = 1usize.checked_shl(k) else {
+            return false;
+        };
+        n >= cs.minimum_rows() && n >= self.usable_rows() + cs.blinding_factors() + 1
+    }
+
+    // The smallest k the circuit fits in
+    pub fn min_k(&self, cs: &ConstraintSystem<TermField>) -> u32 {
+        (0..usize::BITS)
+            .find(|k| self.fits_k(cs, *k))
+            .expect("The circuit does not fit in any table whose size is a usize")
+    }
+
+    // Emits min_k, with lemmas that any k at least min_k gives the row conditions of meets_constraints,
+    // and warns if the k given in the options is too small
+    fn print_min_k(&self, cs: &ConstraintSystem<TermField>) {
+        let (circuit, _) = self.field_types();
+        let min_k = self.min_k(cs);
+        let blinding_factors = cs.blinding_factors();
+        println!("-- The smallest k such that 2^k ≥ {} (cs.minimum_rows) and 2^k - {} ≥ {} (used rows)", cs.minimum_rows(), blinding_factors + 1, self.usable_rows());
+        println!("def min_k : ℕ := {min_k}");
+        println!("lemma sufficient_rows_of_min_k (c: {circuit}) (h: c.k ≥ min_k) : sufficient_rows c := by");
+        println!("  have h_pow : 2 ^ min_k ≤ 2 ^ c.k := Nat.pow_le_pow_right (by norm_num) h");
+        println!("  norm_num [min_k] at h_pow");
+        println!("  unfold sufficient_rows ValidCircuit.n");
+        println!("  omega");
+        println!("lemma usable_rows_of_min_k (c: {circuit}) (h: c.k ≥ min_k) (h_blinding: c.1.num_blinding_factors = {blinding_factors}) :");
+        println!("  c.usable_rows ≥ {} := by", self.usable_rows());
+        println!("  have h_pow : 2 ^ min_k ≤ 2 ^ c.k := Nat.pow_le_pow_right (by norm_num) h");
+        println!("  norm_num [min_k] at h_pow");
+        println!("  unfold ValidCircuit.usable_rows ValidCircuit.n");
+        println!("  omega");
+        if let Some(k) = self.options.k {
+            if !self.fits_k(cs, k) {
+                println!("--WARNING: k = {k} is too small for this circuit, which needs k ≥ {min_k}");
+                eprintln!("WARNING: k = {k} is too small for this circuit, which needs k ≥ {min_k}");
+            }
+        }
+    }
+
+    // The value of a fixed cell, taking fills into account, or None if it was never assigned
+    pub fn fixed_value(&self, col: usize, row: usize) -> Option<&String> {
+        if let Some(value) = self.fixed.get(&col).and_then(|column| column.get(&row)) {
+            return Some(value);
+        }
+        match self.fixed_fill.get(&col) {
+            Some((fill_row, value)) if *fill_row <= row => Some(value),
+            _ => None,
+        }
+    }
+
+    // Extends the current region to cover the row
+    fn record_region_row(&mut self, row: usize) {
+        if self.current_region.is_none() || !self.in_phase(FirstPhase) {
+            return;
+        }
+        if let Some(region) = self.regions.last_mut() {
+            region.rows = Some(match region.rows {
+                Some((start, end)) => (start.min(row), end.max(row)),
+                None => (row, row),
+            });
+        }
+    }
+
+    // The name of the region that covers the row, if any
+    // Regions can overlap when they use disjoint columns, in which case the last one is returned
+    pub fn region_at(&self, row: usize) -> Option<&str> {
+        self.regions
+            .iter()
+            .rev()
+            .find(|region| region.rows.is_some_and(|(start, end)| start <= row && row <= end))
+            .map(|region| region.name.as_str())
     }
 
     fn assert_row_usable(&self, row: usize) {
-        let usable_rows = str::parse::<usize>(
-            &fs::read_to_string(&self.usable_rows_filename)
-                .expect("Failed to read usable_rows file")
-        ).expect("Failed to parse contents of usable_rows file");
+        let usable_rows = self.usable_rows();
         if row >= usable_rows {
             // row+1 because of 0-indexing
             fs::write(&self.usable_rows_filename, (row+1).to_string()).expect("Failed to write usable_rows file");
@@ -532,12 +1466,15 @@ impl Assignment<TermField> for ExtractingAssignment<TermField>
         N: FnOnce() -> NR,
     {
         let x: String = name_fn().into();
-        println!("\n-- Entered region: {x}");
+        self.synthesis_comments.push(format!("\n-- Entered region: {x}"));
+        if self.in_phase(FirstPhase) {
+            self.regions.push(RegionLayout { name: x.clone(), rows: None });
+        }
         self.current_region = Some(x.clone());
     }
 
     fn exit_region(&mut self) {
-        println!("-- Exited region: {}", self.current_region.as_ref().unwrap());
+        self.synthesis_comments.push(format!("-- Exited region: {}", self.current_region.as_ref().unwrap()));
         self.current_region = None;
     }
 
@@ -552,11 +1489,12 @@ impl Assignment<TermField> for ExtractingAssignment<TermField>
         AR: Into<String>,
     {
         if !self.in_phase(FirstPhase) {
-            println!("--WARNING: Attempted to assign selector {} {} outside or first phase", selector.index(), row);
+            self.synthesis_comments.push(format!("--WARNING: Attempted to assign selector {} {} outside or first phase", selector.index(), row));
             return Ok(());
         }
 
         self.assert_row_usable(row);
+        self.record_region_row(row);
 
         self.set_selector(selector.index(), row, annotation().into());
         Ok(())
@@ -571,7 +1509,7 @@ impl Assignment<TermField> for ExtractingAssignment<TermField>
 
         Ok(Value::known(TermField::from(format!(
             "instance_to_field (c.1.Instance {} {})",
-            column.index(),
+            column_ref("instance", column.index(), self.options.typed_columns),
             row
         ))))
     }
@@ -591,6 +1529,7 @@ impl Assignment<TermField> for ExtractingAssignment<TermField>
     {
         if self.in_phase(FirstPhase) {
             self.assert_row_usable(row);
+            self.record_region_row(row);
         }
 
         update_row_annotation(&mut self.advice_column_annotations, column.index(), row, annotation().into());
@@ -619,14 +1558,14 @@ impl Assignment<TermField> for ExtractingAssignment<TermField>
 
         update_row_annotation(&mut self.fixed_column_annotations, column.index(), row, annotation().into());
         self.assert_row_usable(row);
+        self.record_region_row(row);
 
-        to().map(|v| {
-            self.set_fixed_checked(
-                column.index(),
-                row,
-                v.into().evaluate().to_string()
-            );
-        });
+        // An unknown fixed value is still an assigned cell, so it is modelled as a fresh symbol
+        let value = match to().assign() {
+            Ok(v) => v.into().evaluate(),
+            Err(_) => TermField::fresh_symbol(&format!("unknown_fixed_{}_{row}", column.index())),
+        };
+        self.set_fixed_checked(column.index(), row, value.to_string());
         Ok(())
     }
 
@@ -692,126 +1631,1109 @@ impl Assignment<TermField> for ExtractingAssignment<TermField>
 }
 
 pub fn print_preamble(namespace: &str, symbol_names: &[&str], cs: &ConstraintSystem<TermField>) {
-    println!("import Mathlib.Data.Nat.Prime.Defs");
-    println!("import Mathlib.Data.Nat.Prime.Basic");
-    println!("import Mathlib.Data.ZMod.Defs");
-    println!("import Mathlib.Data.ZMod.Basic\n");
+    print_preamble_with_options(namespace, symbol_names, cs, &ExtractionOptions::default())
+}
 
-    println!("set_option linter.unusedVariables false\n");
+pub fn print_preamble_with_options(namespace: &str, symbol_names: &[&str], cs: &ConstraintSystem<TermField>, options: &ExtractionOptions) {
+    print!("{}", preamble(namespace, symbol_names, cs, options));
+}
 
-    println!("namespace {namespace}\n");
+// The Lean text print_preamble_with_options prints
+pub(crate) fn preamble(namespace: &str, symbol_names: &[&str], cs: &ConstraintSystem<TermField>, options: &ExtractionOptions) -> String {
+    let mut lines: Vec<String> = vec![];
+    macro_rules! emit {
+        ($($arg:tt)*) => { lines.push(format!($($arg)*)) };
+    }
+    let typed = options.typed_columns;
+    let field_mode = options.field_mode;
+    let (circuit, value) = (field_mode.circuit_type(), field_mode.value_type());
+    let (circuit_params, circuit_args) = field_mode.circuit_params();
+    let field = field_mode.field_param();
+    // The types columns are indexed by
+    let (advice_col, fixed_col, instance_col, selector_col) = if typed {
+        (
+            format!("Fin {}", cs.num_advice_columns()),
+            format!("Fin {}", cs.num_fixed_columns()),
+            format!("Fin {}", cs.num_instance_columns()),
+            format!("Fin {}", cs.num_selectors()),
+        )
+    } else {
+        ("ℕ".to_string(), "ℕ".to_string(), "ℕ".to_string(), "ℕ".to_string())
+    };
 
-    println!("def S_T_from_P (S T P : ℕ) : Prop :=");
-    println!("  (2^S * T = P - 1) ∧");
-    println!("  (∀ s' t': ℕ, 2^s' * t' = P - 1 → s' ≤ S)");
-    
-    println!("def multiplicative_generator (P: ℕ) (mult_gen: ZMod P) : Prop :=");
-    println!("  mult_gen ^ P = 1");
+    emit!("import Mathlib.Data.Nat.Prime.Defs");
+    emit!("import Mathlib.Data.Nat.Prime.Basic");
+    emit!("import Mathlib.Data.ZMod.Defs");
+    emit!("import Mathlib.GroupTheory.OrderOfElement");
+    if field_mode == FieldMode::Generic {
+        emit!("import Mathlib.Algebra.Field.Basic");
+        emit!("import Mathlib.Data.Fintype.Card");
+    }
+    if typed {
+        emit!("import Mathlib.Data.Fin.VecNotation");
+    }
+    emit!("import Mathlib.Data.ZMod.Basic\n");
+
+    emit!("set_option linter.unusedVariables false\n");
+
+    emit!("namespace {namespace}\n");
+
+    emit!("def S_T_from_P (S T P : ℕ) : Prop :=");
+    emit!("  (2^S * T = P - 1) ∧");
+    emit!("  (∀ s' t': ℕ, 2^s' * t' = P - 1 → s' ≤ S)");
     
-    println!("structure Circuit (P: ℕ) (P_Prime: Nat.Prime P) :=");
-    println!("  Advice: ℕ → ℕ → ZMod P");
-    println!("  AdviceUnassigned: ℕ → ℕ → ZMod P");
-    println!("  AdvicePhase: ℕ → ℕ");
-    println!("  Fixed: ℕ → ℕ → ZMod P");
-    println!("  FixedUnassigned: ℕ → ℕ → ZMod P");
-    println!("  Instance: ℕ → ℕ → ZMod P");
-    println!("  InstanceUnassigned: ℕ → ℕ → ZMod P");
-    println!("  Selector: ℕ → ℕ → ZMod P");
-    println!("  Challenges: (ℕ → ℕ → ZMod P) → ℕ → ℕ → ZMod P");
-    println!("  num_blinding_factors: ℕ");
-    println!("  S: ℕ");
-    println!("  T: ℕ");
-    println!("  k: ℕ");
-    println!("  mult_gen: ZMod P");
+    match field_mode {
+        FieldMode::ZMod => {
+            emit!("def multiplicative_generator (P: ℕ) (mult_gen: ZMod P) : Prop :=");
+            emit!("  orderOf mult_gen = P - 1");
+        },
+        FieldMode::Generic => {
+            emit!("def multiplicative_generator (F: Type) [Field F] [Fintype F] (mult_gen: F) : Prop :=");
+            emit!("  orderOf mult_gen = Fintype.card F - 1");
+        },
+    }
+
+    if typed {
+        // A reference to a column that does not exist is an unknown identifier, and ⟨n, _⟩ for such n fails to prove
+        for (kind, count) in [
+            ("advice", cs.num_advice_columns()),
+            ("fixed", cs.num_fixed_columns()),
+            ("instance", cs.num_instance_columns()),
+            ("selector", cs.num_selectors()),
+        ] {
+            for col in 0..count {
+                emit!("abbrev {kind}_col_{col} : Fin {count} := ⟨{col}, by decide⟩");
+            }
+        }
+    }
+
+    emit!("structure Circuit {circuit_params} :=");
+    emit!("  Advice: {advice_col} → ℕ → {value}");
+    emit!("  AdviceUnassigned: {advice_col} → ℕ → {value}");
+    emit!("  AdvicePhase: {advice_col} → ℕ");
+    emit!("  Fixed: {fixed_col} → ℕ → {value}");
+    emit!("  FixedUnassigned: {fixed_col} → ℕ → {value}");
+    emit!("  Instance: {instance_col} → ℕ → {value}");
+    emit!("  InstanceUnassigned: {instance_col} → ℕ → {value}");
+    emit!("  Selector: {selector_col} → ℕ → {value}");
+    emit!("  Challenges: ({advice_col} → ℕ → {value}) → ℕ → ℕ → {value}");
+    emit!("  num_blinding_factors: ℕ");
+    emit!("  S: ℕ");
+    emit!("  T: ℕ");
+    emit!("  k: ℕ");
+    emit!("  mult_gen: {value}");
     for symbol_name in symbol_names {
-        println!("  sym_{symbol_name}: ZMod P")
+        emit!("  sym_{symbol_name}: {value}")
     }
     
-    println!("variable {{P: ℕ}} {{P_Prime: Nat.Prime P}}");
-    println!("def Circuit.isValid (c: Circuit P P_Prime) : Prop :=");
-    println!("  S_T_from_P c.S c.T P ∧");
-    println!("  multiplicative_generator P c.mult_gen ∧ (");
-    println!("  ∀ advice1 advice2: ℕ → ℕ → ZMod P, ∀ phase: ℕ,");
-    println!("    (∀ row col, (col < {} ∧ c.AdvicePhase col ≤ phase) → advice1 col row = advice2 col row) →", cs.num_advice_columns());
-    println!("    (∀ i, c.Challenges advice1 i phase = c.Challenges advice2 i phase)");
-    println!("  )");
-
-    println!("abbrev ValidCircuit (P: ℕ) (P_Prime: Nat.Prime P) : Type := {{c: Circuit P P_Prime // c.isValid}}");
-    println!("namespace ValidCircuit");
-    println!("def get_advice (c: ValidCircuit P P_Prime) : ℕ → ℕ → ZMod P :=");
-    println!("  λ col row => c.1.Advice col row");
-    println!("def get_fixed (c: ValidCircuit P P_Prime) : ℕ → ℕ → ZMod P :=");
-    println!("  λ col row => c.1.Fixed col row");
-    println!("def get_instance (c: ValidCircuit P P_Prime) : ℕ → ℕ → ZMod P :=");
-    println!("  λ col row => c.1.Instance col row");
-    println!("def get_selector (c: ValidCircuit P P_Prime) : ℕ → ℕ → ZMod P :=");
-    println!("  λ col row => c.1.Selector col row");
-    println!("def get_challenge (c: ValidCircuit P P_Prime) : ℕ → ℕ → ZMod P :=");
-    println!("  λ idx phase => c.1.Challenges c.1.Advice idx phase");
-    println!("def k (c: ValidCircuit P P_Prime) := c.1.k");
-    println!("def n (c: ValidCircuit P P_Prime) := 2^c.k");
-    println!("def usable_rows (c: ValidCircuit P P_Prime) := c.n - (c.1.num_blinding_factors + 1)");
-    println!("def S (c: ValidCircuit P P_Prime) := c.1.S");
-    println!("def T (c: ValidCircuit P P_Prime) := c.1.T");
-    println!("def mult_gen (c: ValidCircuit P P_Prime) := c.1.mult_gen");
-    println!("def root_of_unity (c: ValidCircuit P P_Prime) : ZMod P := c.mult_gen ^ c.T");
-    println!("def delta (c: ValidCircuit P P_Prime) : ZMod P := c.mult_gen ^ (2^c.S)");
-    println!("end ValidCircuit");
-
-    println!("def is_shuffle (c: ValidCircuit P P_Prime) (shuffle: ℕ → ℕ): Prop :=");
-    println!("  ∃ inv: ℕ → ℕ,");
-    println!("  ∀ row: ℕ,");
-    println!("    inv (shuffle row) = row ∧");
-    println!("    (row ≥ c.usable_rows → shuffle row = row)");
-
-    println!("def sufficient_rows (c: ValidCircuit P P_Prime) : Prop :=");
-    println!("  c.n ≥ {} --cs.minimum_rows", cs.minimum_rows());
-
-    println!("--End preamble");
+    emit!("variable {}", field_mode.variables());
+    emit!("def Circuit.isValid (c: Circuit {circuit_args}) : Prop :=");
+    emit!("  S_T_from_P c.S c.T {} ∧", field_mode.order());
+    emit!("  multiplicative_generator {field} c.mult_gen ∧ (");
+    emit!("  ∀ advice1 advice2: {advice_col} → ℕ → {value}, ∀ phase: ℕ,");
+    if typed {
+        emit!("    (∀ row col, c.AdvicePhase col ≤ phase → advice1 col row = advice2 col row) →");
+    } else {
+        emit!("    (∀ row col, (col < {} ∧ c.AdvicePhase col ≤ phase) → advice1 col row = advice2 col row) →", cs.num_advice_columns());
+    }
+    emit!("    (∀ i, c.Challenges advice1 i phase = c.Challenges advice2 i phase)");
+    emit!("  )");
+
+    emit!("abbrev ValidCircuit {circuit_params} : Type := {{c: Circuit {circuit_args} // c.isValid}}");
+    emit!("namespace ValidCircuit");
+    emit!("def get_advice (c: {circuit}) : {advice_col} → ℕ → {value} :=");
+    emit!("  λ col row => c.1.Advice col row");
+    emit!("def get_fixed (c: {circuit}) : {fixed_col} → ℕ → {value} :=");
+    emit!("  λ col row => c.1.Fixed col row");
+    emit!("def get_instance (c: {circuit}) : {instance_col} → ℕ → {value} :=");
+    emit!("  λ col row => c.1.Instance col row");
+    emit!("def get_selector (c: {circuit}) : {selector_col} → ℕ → {value} :=");
+    emit!("  λ col row => c.1.Selector col row");
+    emit!("def get_challenge (c: {circuit}) : ℕ → ℕ → {value} :=");
+    emit!("  λ idx phase => c.1.Challenges c.1.Advice idx phase");
+    emit!("def k (c: {circuit}) := c.1.k");
+    emit!("def n (c: {circuit}) := 2^c.k");
+    emit!("def usable_rows (c: {circuit}) := c.n - (c.1.num_blinding_factors + 1)");
+    emit!("def S (c: {circuit}) := c.1.S");
+    emit!("def T (c: {circuit}) := c.1.T");
+    emit!("def mult_gen (c: {circuit}) := c.1.mult_gen");
+    emit!("def root_of_unity (c: {circuit}) : {value} := c.mult_gen ^ c.T");
+    emit!("def delta (c: {circuit}) : {value} := c.mult_gen ^ (2^c.S)");
+    // Inverses are written with these rather than ZMod.inv, so that the same text works over any field
+    emit!("def two_inv (c: {circuit}) : {value} := 2⁻¹");
+    emit!("def inv (c: {circuit}) (x: {value}) : {value} := x⁻¹");
+    // Follows from isValid, as mult_gen has order 2^S * T
+    let field_size = match field_mode {
+        FieldMode::ZMod => "P_Prime.two_le",
+        FieldMode::Generic => "Fintype.one_lt_card (α := F)",
+    };
+    emit!("lemma root_of_unity_order (c: {circuit}) : orderOf c.root_of_unity = 2 ^ c.S := by");
+    emit!("  have h := c.2");
+    emit!("  unfold Circuit.isValid S_T_from_P multiplicative_generator at h");
+    emit!("  obtain ⟨⟨h_ST, _⟩, h_gen, _⟩ := h");
+    emit!("  simp only [root_of_unity, mult_gen, T, S]");
+    emit!("  have h_T : c.1.T ≠ 0 := by");
+    emit!("    rintro h_zero");
+    emit!("    rw [h_zero, mul_zero] at h_ST");
+    emit!("    have := {field_size}");
+    emit!("    omega");
+    emit!("  rw [orderOf_pow_of_dvd h_T (by rw [h_gen, ← h_ST]; exact Dvd.intro_left _ rfl), h_gen, ← h_ST]");
+    emit!("  exact Nat.mul_div_cancel _ (Nat.pos_of_ne_zero h_T)");
+    emit!("end ValidCircuit");
+    if field_mode == FieldMode::Generic {
+        emit!("-- ZMod P for a prime P is one instance of the fields the model is over");
+        emit!("example (P: ℕ) [Fact P.Prime] [NeZero P] (c: ValidCircuit (ZMod P)) : ZMod P := c.delta");
+    }
+
+    if let Some(profile) = &options.field_profile {
+        emit!("-- The constants of {}, as halo2 defines them", profile.name);
+        emit!("def field_profile (c: {circuit}) : Prop :=");
+        emit!("  {} = {} ∧", field_mode.order(), profile.modulus);
+        emit!("  c.S = {} ∧", profile.s);
+        emit!("  c.T = {} ∧", profile.t);
+        emit!("  c.mult_gen = {} ∧", profile.mult_gen);
+        emit!("  c.two_inv = {} ∧", profile.two_inv);
+        emit!("  c.root_of_unity = {} ∧", profile.root_of_unity);
+        emit!("  c.root_of_unity⁻¹ = {} ∧", profile.root_of_unity_inv);
+        emit!("  c.delta = {}", profile.delta);
+    }
+
+    emit!("def is_shuffle (c: {circuit}) (shuffle: ℕ → ℕ): Prop :=");
+    emit!("  ∃ inv: ℕ → ℕ,");
+    emit!("  ∀ row: ℕ,");
+    emit!("    inv (shuffle row) = row ∧");
+    emit!("    (row ≥ c.usable_rows → shuffle row = row)");
+
+    emit!("def sufficient_rows (c: {circuit}) : Prop :=");
+    emit!("  c.n ≥ {} --cs.minimum_rows", cs.minimum_rows());
+
+    emit!("--End preamble");
+    lines.join("\n") + "\n"
 }
 
+// Reads the usable rows from ./usable_rows, where extractions used to record them
 pub fn print_postamble(name: &str, cs: &ConstraintSystem<TermField>) {
     let usable_rows = str::parse::<usize>(&fs::read_to_string("./usable_rows").expect("Failed to read usable_rows")).expect("Failed to parse usable_rows");
+    print_postamble_with_options(name, cs, usable_rows, &ExtractionOptions::default())
+}
+
+pub fn print_postamble_with_options(name: &str, cs: &ConstraintSystem<TermField>, usable_rows: usize, options: &ExtractionOptions) {
+    print!("{}", postamble(name, cs, usable_rows, options));
+}
 
-    println!("def meets_constraints (c: ValidCircuit P P_Prime): Prop :=");
-    println!("  sufficient_rows c ∧");
-    println!("  c.1.num_blinding_factors = {} ∧", cs.blinding_factors());
-    println!("  c.1.Selector = selector_func c ∧");
-    println!("  c.1.Fixed = fixed_func c ∧");
-    println!("  c.1.AdvicePhase = advice_phase c ∧");
-    println!("  c.usable_rows ≥ {usable_rows} ∧");
-    println!("  all_gates c ∧");
-    println!("  all_copy_constraints c ∧");
-    println!("  all_lookups c ∧");
-    println!("  all_shuffles c ∧");
-    println!("  ∀ col row: ℕ, (row < c.n ∧ row ≥ c.usable_rows) → c.1.Instance col row = c.1.InstanceUnassigned col row");
-    println!("end {name}");
+// The Lean text print_postamble_with_options prints
+pub(crate) fn postamble(name: &str, cs: &ConstraintSystem<TermField>, usable_rows: usize, options: &ExtractionOptions) -> String {
+    let mut lines: Vec<String> = vec![];
+    macro_rules! emit {
+        ($($arg:tt)*) => { lines.push(format!($($arg)*)) };
+    }
+
+    emit!("def meets_constraints (c: {}): Prop :=", options.field_mode.circuit_type());
+    let conjuncts = meets_constraints_conjuncts(cs, usable_rows, options);
+    for (idx, conjunct) in conjuncts.iter().enumerate() {
+        if idx + 1 == conjuncts.len() {
+            emit!("  {conjunct}");
+        } else if conjunct.starts_with('∀') {
+            // The binder would otherwise extend over the rest of the conjunction
+            emit!("  ({conjunct}) ∧");
+        } else {
+            emit!("  {conjunct} ∧");
+        }
+    }
+    if options.field_mode == FieldMode::Generic {
+        emit!("-- ZMod P for a prime P is one instance of the fields the model is over");
+        emit!("example (P: ℕ) [Fact P.Prime] [NeZero P] (c: ValidCircuit (ZMod P)) : Prop := meets_constraints c");
+    }
+    emit!("end {name}");
+    lines.join("\n") + "\n"
+}
+
+// The conjuncts of meets_constraints in order, which spec_skeleton also uses to project them out
+pub(crate) fn meets_constraints_conjuncts(cs: &ConstraintSystem<TermField>, usable_rows: usize, options: &ExtractionOptions) -> Vec<String> {
+    let mut conjuncts = vec![
+        "sufficient_rows c".to_string(),
+        format!("c.1.num_blinding_factors = {}", cs.blinding_factors()),
+        "c.1.Selector = selector_func c".to_string(),
+        "c.1.Fixed = fixed_func c".to_string(),
+        "c.1.AdvicePhase = advice_phase c".to_string(),
+        format!("c.usable_rows ≥ {usable_rows}"),
+        "all_gates c".to_string(),
+        "all_copy_constraints c".to_string(),
+        "all_lookups c".to_string(),
+        "all_shuffles c".to_string(),
+        "∀ col, ∀ row: ℕ, (row < c.n ∧ row ≥ c.usable_rows) → c.1.Instance col row = c.1.InstanceUnassigned col row".to_string(),
+    ];
+    if options.field_profile.is_some() {
+        conjuncts.push("field_profile c".to_string());
+    }
+    conjuncts
+}
+
+// Escapes text for a Lean string literal
+fn escape_lean_string(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+// Prints a def from Fin columns as a vector with one entry per column, so that it has no catch-all case
+// Each entry is (term, column annotation), and may refer to the bound variables
+fn print_fin_table(signature: &str, binders: &str, codomain: &str, entries: &[(String, Option<String>)]) {
+    print!("{}", fin_table(signature, binders, codomain, entries));
+}
+
+// The Lean text print_fin_table prints
+fn fin_table(signature: &str, binders: &str, codomain: &str, entries: &[(String, Option<String>)]) -> String {
+    let mut lines = vec![format!("def {signature} :=")];
+    if entries.is_empty() {
+        lines.push(format!("  λ {binders} => (![] : Fin 0 → {codomain}) col"));
+        return lines.join("\n") + "\n";
+    }
+    lines.push(format!("  λ {binders} => (!["));
+    for (idx, (entry, annotation)) in entries.iter().enumerate() {
+        let separator = if idx + 1 == entries.len() { "" } else { "," };
+        match annotation {
+            Some(annotation) => lines.push(format!("    {entry}{separator} {}", make_lean_comment(annotation))),
+            None => lines.push(format!("    {entry}{separator}")),
+        }
+    }
+    lines.push(format!("  ] : Fin {} → {codomain}) col", entries.len()));
+    lines.join("\n") + "\n"
+}
+
+// A branch of the if-chain print_fixed emits for a column, either a run of rows sharing a value or a helper def grouping several
+enum FixedBranch {
+    Run { idx: usize, start: usize, end: Option<usize> },
+    Helper { name: String, start: usize, end: usize, branches: Vec<FixedBranch> },
+}
+
+impl FixedBranch {
+    fn start(&self) -> usize {
+        match self {
+            FixedBranch::Run { start, .. } | FixedBranch::Helper { start, .. } => *start,
+        }
+    }
+
+    fn end(&self) -> usize {
+        match self {
+            FixedBranch::Run { start, end, .. } => end.unwrap_or(*start),
+            FixedBranch::Helper { end, .. } => *end,
+        }
+    }
+
+    // The condition of the branch, exactly as print_fixed writes it
+    fn condition(&self) -> String {
+        match self {
+            FixedBranch::Run { start, end: None, .. } => format!("row = {start}"),
+            _ => format!("row ≥ {} ∧ row ≤ {}", self.start(), self.end()),
+        }
+    }
+}
+
+// The branches of fixed_func_col_N, grouped into helper defs the same way print_fixed groups them
+fn fixed_branches(col: usize, runs: &[(String, usize, Option<usize>)]) -> Vec<FixedBranch> {
+    let mut branches = runs
+        .iter()
+        .enumerate()
+        .map(|(idx, (_, start, end))| FixedBranch::Run { idx, start: *start, end: *end })
+        .collect_vec();
+    while branches.len() > GROUPING_SIZE {
+        let mut grouped = vec![];
+        while branches.len() > GROUPING_SIZE {
+            let group = branches.drain(..GROUPING_SIZE).collect_vec();
+            let (start, end) = (group[0].start(), group[GROUPING_SIZE-1].end());
+            grouped.push(FixedBranch::Helper { name: format!("fixed_func_col_{col}_{start}_to_{end}"), start, end, branches: group });
+        }
+        grouped.append(&mut branches);
+        branches = grouped;
+    }
+    branches
+}
+
+// The consecutive runs of enabled rows of a selector, with inclusive ends
+fn selector_runs(row_set: &BTreeMap<usize, String>) -> Vec<(usize, usize)> {
+    let mut runs: Vec<(usize, usize)> = vec![];
+    for &row in row_set.keys() {
+        match runs.last_mut() {
+            Some((_, end)) if *end + 1 == row => *end = row,
+            _ => runs.push((row, row)),
+        }
+    }
+    runs
+}
+
+// Emits a def for each distinct name, such as gate_fibonacci_add, as the conjunction of the numbered defs from that name
+// meets_constraints still uses the numbered defs, these are for referring to constraints by name in proofs
+fn print_named_props(prefix: &str, circuit: &str, named: &[(String, Vec<String>)], reserved: &HashSet<String>) {
+    for (name, members) in named {
+        let def_name = format!("{prefix}{}", mangle_identifier(name));
+        if reserved.contains(&def_name) {
+            println!("--WARNING: no def for \"{name}\" as {def_name} is already taken");
+            continue;
+        }
+        let body = if members.is_empty() {
+            "true".to_string()
+        } else {
+            members.iter().map(|member| format!("{member} c")).join(" ∧ ")
+        };
+        println!("def {def_name} (c: {circuit}) : Prop := {body}");
+    }
+}
+
+// The name of the def emitted for a shuffle
+pub fn shuffle_name(name: &str) -> String {
+    format!("shuffle_{}", mangle_identifier(name))
+}
+
+// Formats `∀ row: ℕ, poly = 0`, moving the polynomial onto its own indented lines if it is too long
+fn format_gate_polynomial(polynomial: &LeanTerm, line_width: usize) -> String {
+    let flat = format!("∀ row: ℕ, {polynomial} = 0");
+    if 2 + flat.chars().count() <= line_width {
+        flat
+    } else {
+        format!("∀ row: ℕ,\n    {} = 0", polynomial.render(4, line_width))
+    }
+}
+
+// Formats `∀ row: ℕ, c.get_selector s row = 1 → body = 0`, moving the body onto its own indented lines if it is too long
+fn format_guarded_gate(selectors: &[String], body: &LeanTerm, line_width: usize) -> String {
+    let hypotheses = selectors.iter().map(|selector| format!("c.get_selector {selector} row = 1 →")).join(" ");
+    let flat = format!("∀ row: ℕ, {hypotheses} {body} = 0");
+    if 2 + flat.chars().count() <= line_width {
+        flat
+    } else {
+        format!("∀ row: ℕ, {hypotheses}\n    {} = 0", body.render(4, line_width))
+    }
+}
+
+// Formats a gate polynomial with its repeated sub-expressions bound by let, in dependency order
+fn format_gate_with_lets(dag: &TermDag, root: usize, shared: &[usize], names: &HashMap<usize, String>, value: &str, line_width: usize) -> String {
+    if shared.is_empty() {
+        return format_gate_polynomial(&dag.to_term(root, names), line_width);
+    }
+
+    let mut res = String::from("∀ row: ℕ,");
+    for id in shared {
+        let name = &names[id];
+        let body = dag.to_term(*id, names);
+        let flat = format!("let {name} : {value} := {body}");
+        if 4 + flat.chars().count() <= line_width {
+            res.push_str(&format!("\n    {flat}"));
+        } else {
+            res.push_str(&format!("\n    let {name} : {value} :=\n      {}", body.render(6, line_width)));
+        }
+    }
+    res.push_str(&format!("\n    {} = 0", dag.to_term(root, names).render(4, line_width)));
+    res
+}
+
+// Formats `(lhs) = (rhs)` for lookups and shuffles, starting at column indent
+fn format_tuple_equation(lhs: &[LeanTerm], rhs: &[LeanTerm], indent: usize, line_width: usize) -> String {
+    let lhs = render_tuple(lhs, indent, line_width);
+    let flat_rhs = render_tuple(rhs, 0, usize::MAX);
+    if !lhs.contains('\n') && indent + lhs.chars().count() + 3 + flat_rhs.chars().count() <= line_width {
+        format!("{lhs} = {flat_rhs}")
+    } else {
+        let rhs_indent = indent + 2;
+        format!("{lhs}\n{}= {}", " ".repeat(rhs_indent), render_tuple(rhs, rhs_indent + 2, line_width))
+    }
 }
 
 pub fn expression_to_value_string(expr: &Expression<TermField>, row_name: &str) -> String {
-    let format_lookup = |identifier, column, rotation: i32| {
+    expression_to_term(expr, row_name).to_string()
+}
+
+pub fn expression_to_term(expr: &Expression<TermField>, row_name: &str) -> LeanTerm {
+    expression_to_term_with_columns(expr, row_name, false)
+}
+
+// A column index as emitted, either a natural number or one of the Fin constants declared in the typed preamble
+pub fn column_ref(kind: &str, column: usize, typed_columns: bool) -> String {
+    if typed_columns {
+        format!("{kind}_col_{column}")
+    } else {
+        column.to_string()
+    }
+}
+
+pub fn expression_to_term_with_columns(expr: &Expression<TermField>, row_name: &str, typed_columns: bool) -> LeanTerm {
+    let format_lookup = |identifier, kind, column, rotation: i32| {
+        let column = column_ref(kind, column, typed_columns);
         if rotation == 0 {
-            format!("{} {} {row_name}", identifier, column)
+            LeanTerm::app(format!("{} {} {row_name}", identifier, column))
         } else if rotation > 0 {
-            format!("{} {} (({row_name} + {}) % c.n)", identifier, column, rotation)
+            LeanTerm::app(format!("{} {} (({row_name} + {}) % c.n)", identifier, column, rotation))
         } else {
-            format!("{} {} (({row_name} + c.n - ({} % c.n)) % c.n)", identifier, column, -rotation)
+            LeanTerm::app(format!("{} {} (({row_name} + c.n - ({} % c.n)) % c.n)", identifier, column, -rotation))
         }
     };
+    let recurse = |expr: &Expression<TermField>| expression_to_term_with_columns(expr, row_name, typed_columns);
 
     match expr {
-        Expression::Constant(value) => format!("({})", value),
-        Expression::Selector(selector) => format!("c.get_selector {} {row_name}", selector.0),
-        Expression::Fixed(query) => format_lookup("c.get_fixed", query.column_index(), query.rotation().0),
-        Expression::Advice(query) => format_lookup("c.get_advice", query.column_index(), query.rotation().0),
-        Expression::Instance(query) => format_lookup("c.get_instance", query.column_index(), query.rotation().0),
-        Expression::Challenge(challenge) => format!("c.get_challenge {} {}", challenge.index(), challenge.phase()),
-        Expression::Negated(expression) => format!("-({})", expression_to_value_string(expression, row_name)),
+        Expression::Constant(value) => LeanTerm::text(value.to_string()),
+        Expression::Selector(selector) => LeanTerm::app(format!("c.get_selector {} {row_name}", column_ref("selector", selector.0, typed_columns))),
+        Expression::Fixed(query) => format_lookup("c.get_fixed", "fixed", query.column_index(), query.rotation().0),
+        Expression::Advice(query) => format_lookup("c.get_advice", "advice", query.column_index(), query.rotation().0),
+        Expression::Instance(query) => format_lookup("c.get_instance", "instance", query.column_index(), query.rotation().0),
+        Expression::Challenge(challenge) => LeanTerm::app(format!("c.get_challenge {} {}", challenge.index(), challenge.phase())),
+        Expression::Negated(expression) => LeanTerm::neg(recurse(expression)),
         Expression::Sum(expression, expression1) =>
-            format!("({}) + ({})", expression_to_value_string(expression, row_name), expression_to_value_string(expression1, row_name)),
+            LeanTerm::binary(BinaryOp::Add, recurse(expression), recurse(expression1)),
         Expression::Product(expression, expression1) =>
-            format!("({}) * ({})", expression_to_value_string(expression, row_name), expression_to_value_string(expression1, row_name)),
+            LeanTerm::binary(BinaryOp::Mul, recurse(expression), recurse(expression1)),
         Expression::Scaled(expression, factor) =>
-        format!("({}) * ({})", factor.to_string(), expression_to_value_string(expression, row_name)),
+            LeanTerm::binary(BinaryOp::Mul, LeanTerm::text(factor.to_string()), recurse(expression)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use halo2_proofs::circuit::{Layouter, SimpleFloorPlanner};
+    use halo2_proofs::poly::Rotation;
+
+    use super::*;
+
+    // A fixed column with the named symbol at row 0 and an unknown value at row 1
+    // A symbol named unused is created too, but never assigned
+    struct SymbolCircuit {
+        name: &'static str,
+    }
+
+    impl Circuit<TermField> for SymbolCircuit {
+        type Config = Column<Fixed>;
+        type FloorPlanner = SimpleFloorPlanner;
+        type Params = ();
+
+        fn without_witnesses(&self) -> Self {
+            Self { name: self.name }
+        }
+
+        fn configure(meta: &mut ConstraintSystem<TermField>) -> Self::Config {
+            meta.fixed_column()
+        }
+
+        fn synthesize(&self, fixed: Self::Config, mut layouter: impl Layouter<TermField>) -> Result<(), halo2_frontend::plonk::Error> {
+            layouter.assign_region(
+                || "symbols",
+                |mut region| {
+                    TermField::create_symbol("unused");
+                    region.assign_fixed(|| "named", fixed, 0, || Value::known(TermField::create_symbol(self.name)))?;
+                    region.assign_fixed(|| "unknown", fixed, 1, Value::<TermField>::unknown)?;
+                    Ok(())
+                },
+            )
+        }
+    }
+
+    // A fixed column assigned on the given number of rows
+    struct RowsCircuit {
+        rows: usize,
+    }
+
+    impl Circuit<TermField> for RowsCircuit {
+        type Config = Column<Fixed>;
+        type FloorPlanner = SimpleFloorPlanner;
+        type Params = ();
+
+        fn without_witnesses(&self) -> Self {
+            Self { rows: self.rows }
+        }
+
+        fn configure(meta: &mut ConstraintSystem<TermField>) -> Self::Config {
+            meta.fixed_column()
+        }
+
+        fn synthesize(&self, fixed: Self::Config, mut layouter: impl Layouter<TermField>) -> Result<(), halo2_frontend::plonk::Error> {
+            layouter.assign_region(
+                || "rows",
+                |mut region| {
+                    for row in 0..self.rows {
+                        region.assign_fixed(|| "row", fixed, row, || Value::known(TermField::from(row as u64)))?;
+                    }
+                    Ok(())
+                },
+            )
+        }
+    }
+
+    // An advice column looked up in a fixed table column, which holds table at its first rows
+    struct LookupCircuit {
+        table: Vec<u64>,
+        inputs: usize,
+    }
+
+    // Looks the current advice cell up in the fixed cell at the rotation
+    fn configure_lookup(meta: &mut ConstraintSystem<TermField>, rotation: Rotation) -> (Column<Advice>, Column<Fixed>) {
+        let (advice, fixed) = (meta.advice_column(), meta.fixed_column());
+        meta.lookup_any("table", |meta| vec![(meta.query_advice(advice, Rotation::cur()), meta.query_fixed(fixed, rotation))]);
+        (advice, fixed)
+    }
+
+    impl Circuit<TermField> for LookupCircuit {
+        type Config = (Column<Advice>, Column<Fixed>);
+        type FloorPlanner = SimpleFloorPlanner;
+        type Params = ();
+
+        fn without_witnesses(&self) -> Self {
+            Self { table: self.table.clone(), inputs: self.inputs }
+        }
+
+        fn configure(meta: &mut ConstraintSystem<TermField>) -> Self::Config {
+            configure_lookup(meta, Rotation::cur())
+        }
+
+        fn synthesize(&self, (advice, fixed): Self::Config, mut layouter: impl Layouter<TermField>) -> Result<(), halo2_frontend::plonk::Error> {
+            layouter.assign_region(
+                || "lookup",
+                |mut region| {
+                    for (row, value) in self.table.iter().enumerate() {
+                        region.assign_fixed(|| "table", fixed, row, || Value::known(TermField::from(*value)))?;
+                    }
+                    for row in 0..self.inputs {
+                        region.assign_advice(|| "input", advice, row, || Value::known(TermField::zero()))?;
+                    }
+                    Ok(())
+                },
+            )
+        }
+    }
+
+    // A selector enabled at the given rows, next to a fixed column holding the given values from row 0
+    struct UnfoldingCircuit {
+        selector_rows: Vec<usize>,
+        fixed: Vec<u64>,
+    }
+
+    impl Circuit<TermField> for UnfoldingCircuit {
+        type Config = (Selector, Column<Fixed>);
+        type FloorPlanner = SimpleFloorPlanner;
+        type Params = ();
+
+        fn without_witnesses(&self) -> Self {
+            Self { selector_rows: self.selector_rows.clone(), fixed: self.fixed.clone() }
+        }
+
+        fn configure(meta: &mut ConstraintSystem<TermField>) -> Self::Config {
+            (meta.selector(), meta.fixed_column())
+        }
+
+        fn synthesize(&self, (selector, fixed): Self::Config, mut layouter: impl Layouter<TermField>) -> Result<(), halo2_frontend::plonk::Error> {
+            layouter.assign_region(
+                || "unfolding",
+                |mut region| {
+                    for row in &self.selector_rows {
+                        selector.enable(&mut region, *row)?;
+                    }
+                    for (row, value) in self.fixed.iter().enumerate() {
+                        region.assign_fixed(|| "fixed", fixed, row, || Value::known(TermField::from(*value)))?;
+                    }
+                    Ok(())
+                },
+            )
+        }
+    }
+
+    #[test]
+    fn test_unfolding_lemmas() {
+        let if_chain = BTreeMap::from([(0, vec![])]);
+        let circuit = UnfoldingCircuit { selector_rows: vec![0, 1, 3], fixed: vec![1, 1, 5, 1, 1] };
+        let (_, extraction) = ExtractingAssignment::synthesize_circuit(&circuit, &ExtractionOptions::default()).unwrap();
+        assert_eq!(
+            extraction.unfolding_lemmas(&if_chain),
+            [
+                "-- Unfolding lemmas",
+                "lemma selector_func_col_0_eq_one_iff (c: ValidCircuit P P_Prime) (row: ℕ) : selector_func_col_0 c row = 1 ↔ row < 2 ∨ (row ≥ 3 ∧ row < 4) := by",
+                "  haveI : Fact P.Prime := ⟨P_Prime⟩",
+                "  simp only [selector_func_col_0]",
+                "  split_ifs <;> (try simp) <;> omega",
+                "lemma fixed_func_col_0_run_0 (c: ValidCircuit P P_Prime) (row: ℕ) (h_start: row ≥ 0) (h_end: row ≤ 1) :",
+                "  fixed_func_col_0 c row = 1 := by",
+                "  simp only [fixed_func_col_0]",
+                "  rw [if_pos (show row ≥ 0 ∧ row ≤ 1 by omega)]",
+                "lemma fixed_func_col_0_run_1 (c: ValidCircuit P P_Prime) (row: ℕ) (h_start: row ≥ 2) (h_end: row ≤ 2) :",
+                "  fixed_func_col_0 c row = 5 := by",
+                "  simp only [fixed_func_col_0]",
+                "  rw [if_neg (show ¬(row ≥ 0 ∧ row ≤ 1) by omega), if_pos (show row = 2 by omega)]",
+                "lemma fixed_func_col_0_run_2 (c: ValidCircuit P P_Prime) (row: ℕ) (h_start: row ≥ 3) (h_end: row ≤ 4) :",
+                "  fixed_func_col_0 c row = 1 := by",
+                "  simp only [fixed_func_col_0]",
+                "  rw [if_neg (show ¬(row ≥ 0 ∧ row ≤ 1) by omega), if_neg (show ¬(row = 2) by omega), if_pos (show row ≥ 3 ∧ row ≤ 4 by omega)]",
+                "",
+            ]
+            .join("\n")
+        );
+
+        // The first ten runs are grouped into a helper def, whose lemma the lemmas of its runs rewrite with
+        let circuit = UnfoldingCircuit { selector_rows: vec![0], fixed: (0..12).collect() };
+        let (_, extraction) = ExtractingAssignment::synthesize_circuit(&circuit, &ExtractionOptions::default()).unwrap();
+        let lemmas = extraction.unfolding_lemmas(&if_chain);
+        let expected = [
+            [
+                "lemma fixed_func_col_0_0_to_9_unfold (c: ValidCircuit P P_Prime) (row: ℕ) (h_start: row ≥ 0) (h_end: row ≤ 9) :",
+                "  fixed_func_col_0 c row = fixed_func_col_0_0_to_9 c row := by",
+                "  simp only [fixed_func_col_0]",
+                "  rw [if_pos (show row ≥ 0 ∧ row ≤ 9 by omega)]",
+            ]
+            .join("\n"),
+            [
+                "lemma fixed_func_col_0_run_3 (c: ValidCircuit P P_Prime) (row: ℕ) (h_start: row ≥ 3) (h_end: row ≤ 3) :",
+                "  fixed_func_col_0 c row = 3 := by",
+                "  rw [fixed_func_col_0_0_to_9_unfold c row (by omega) (by omega)]",
+                "  simp only [fixed_func_col_0_0_to_9]",
+                "  rw [if_neg (show ¬(row = 0) by omega), if_neg (show ¬(row = 1) by omega), if_neg (show ¬(row = 2) by omega), if_pos (show row = 3 by omega)]",
+            ]
+            .join("\n"),
+            [
+                "lemma fixed_func_col_0_run_11 (c: ValidCircuit P P_Prime) (row: ℕ) (h_start: row ≥ 11) (h_end: row ≤ 11) :",
+                "  fixed_func_col_0 c row = 11 := by",
+                "  simp only [fixed_func_col_0]",
+                "  rw [if_neg (show ¬(row ≥ 0 ∧ row ≤ 9) by omega), if_neg (show ¬(row = 10) by omega), if_pos (show row = 11 by omega)]",
+            ]
+            .join("\n"),
+        ];
+        for lemma in expected {
+            assert!(lemmas.contains(&lemma), "missing\n{lemma}\nin\n{lemmas}");
+        }
+    }
+
+    // s * (a[next] + f * i[prev]), with instance cell 0 copied into f at row 0 through an advice cell
+    struct QueryCircuit;
+
+    impl Circuit<TermField> for QueryCircuit {
+        type Config = (Column<Advice>, Column<Fixed>, Column<Instance>, Selector);
+        type FloorPlanner = SimpleFloorPlanner;
+        type Params = ();
+
+        fn without_witnesses(&self) -> Self {
+            Self
+        }
+
+        fn configure(meta: &mut ConstraintSystem<TermField>) -> Self::Config {
+            let (a, f, i, s) = (meta.advice_column(), meta.fixed_column(), meta.instance_column(), meta.complex_selector());
+            meta.enable_equality(a);
+            meta.create_gate("queries", |meta| {
+                let product = meta.query_fixed(f, Rotation::cur()) * meta.query_instance(i, Rotation::prev());
+                vec![meta.query_selector(s) * (meta.query_advice(a, Rotation::next()) + product)]
+            });
+            (a, f, i, s)
+        }
+
+        fn synthesize(&self, (a, f, i, s): Self::Config, mut layouter: impl Layouter<TermField>) -> Result<(), halo2_frontend::plonk::Error> {
+            layouter.assign_region(
+                || "queries",
+                |mut region| {
+                    s.enable(&mut region, 1)?;
+                    let cell = region.assign_advice_from_instance(|| "instance", i, 0, a, 0)?;
+                    region.assign_fixed(|| "from instance", f, 0, || cell.value().copied())?;
+                    Ok(())
+                },
+            )
+        }
+    }
+
+    #[test]
+    fn test_typed_column_queries() {
+        let options = ExtractionOptions { typed_columns: true, ..ExtractionOptions::default() };
+        let (cs, extraction) = ExtractingAssignment::synthesize_circuit(&QueryCircuit, &options).unwrap();
+        assert_eq!(
+            extraction.expression_to_term(&cs.gates()[0].polynomials()[0], "row").to_string(),
+            "c.get_selector selector_col_0 row * (c.get_advice advice_col_0 ((row + 1) % c.n) + \
+             c.get_fixed fixed_col_0 row * c.get_instance instance_col_0 ((row + c.n - (1 % c.n)) % c.n))"
+        );
+        // Values read from instance cells name their column the same way
+        assert_eq!(extraction.fixed_value(0, 0).map(String::as_str), Some("instance_to_field (c.1.Instance instance_col_0 0)"));
+    }
+
+    // Advice columns whose annotations collide once mangled, and a fixed column annotated with Lean syntax
+    struct NamesCircuit;
+
+    impl Circuit<TermField> for NamesCircuit {
+        type Config = ([Column<Advice>; 3], Column<Fixed>);
+        type FloorPlanner = SimpleFloorPlanner;
+        type Params = ();
+
+        fn without_witnesses(&self) -> Self {
+            Self
+        }
+
+        fn configure(meta: &mut ConstraintSystem<TermField>) -> Self::Config {
+            ([meta.advice_column(), meta.advice_column(), meta.advice_column()], meta.fixed_column())
+        }
+
+        fn synthesize(&self, (advice, fixed): Self::Config, mut layouter: impl Layouter<TermField>) -> Result<(), halo2_frontend::plonk::Error> {
+            layouter.assign_region(
+                || "names",
+                |mut region| {
+                    for (column, name) in advice.iter().zip(["x y", "x y", "x y advice 1"]) {
+                        region.name_column(|| name, *column);
+                    }
+                    region.name_column(|| "say \"hi\" -/", fixed);
+                    for (row, name) in [(0, "v"), (1, "v"), (2, "w")] {
+                        region.assign_advice(|| name, advice[0], row, || Value::known(TermField::zero()))?;
+                    }
+                    region.assign_fixed(|| "end -/ here", fixed, 0, || Value::known(TermField::zero()))?;
+                    Ok(())
+                },
+            )
+        }
+    }
+
+    #[test]
+    fn test_column_names() {
+        let (cs, extraction) = ExtractingAssignment::synthesize_circuit(&NamesCircuit, &ExtractionOptions::default()).unwrap();
+        assert_eq!(
+            extraction.column_names(&cs),
+            [
+                "def advice_column_name : ℕ → String",
+                "  | 0 => \"x y\"",
+                "  | 1 => \"x y\"",
+                "  | 2 => \"x y advice 1\"",
+                "  | _ => \"\"",
+                "/--",
+                "advice column 0: x y",
+                "rows 0-1: v",
+                "row 2: w",
+                "-/",
+                "abbrev col_x_y : ℕ := 0",
+                "/-- advice column 1: x y -/",
+                "abbrev col_x_y_advice_1 : ℕ := 1",
+                // The suffixed name of column 1 is taken too, so the suffix is added again
+                "/-- advice column 2: x y advice 1 -/",
+                "abbrev col_x_y_advice_1_advice_2 : ℕ := 2",
+                "def fixed_column_name : ℕ → String",
+                "  | 0 => \"say \\\"hi\\\" -/\"",
+                "  | _ => \"\"",
+                "/--",
+                "fixed column 0: say \"hi\" - /",
+                "row 0: end - / here",
+                "-/",
+                "abbrev col_say_'22'hi'22'_'2d''2f' : ℕ := 0",
+                "def instance_column_name : ℕ → String",
+                "  | _ => \"\"",
+                "",
+            ]
+            .join("\n")
+        );
+    }
+
+    #[test]
+    fn test_selector_binary() {
+        let tail = |proof: &str| {
+            [
+                "lemma selector_binary (c: ValidCircuit P P_Prime) (h_selector: c.1.Selector = selector_func c) :",
+                "  ∀ col row, c.get_selector col row = 0 ∨ c.get_selector col row = 1 := by",
+                "  intro col row",
+                "  simp only [ValidCircuit.get_selector, h_selector, selector_func]",
+                proof,
+                "",
+            ]
+            .join("\n")
+        };
+
+        let circuit = UnfoldingCircuit { selector_rows: vec![1], fixed: vec![] };
+        let (_, extraction) = ExtractingAssignment::synthesize_circuit(&circuit, &ExtractionOptions::default()).unwrap();
+        let binary_0 = [
+            "lemma selector_func_col_0_binary (c: ValidCircuit P P_Prime) (row: ℕ) :",
+            "  selector_func_col_0 c row = 0 ∨ selector_func_col_0 c row = 1 := by",
+            "  simp only [selector_func_col_0]",
+            "  split_ifs <;> simp",
+        ]
+        .join("\n");
+        assert_eq!(
+            extraction.selector_binary(),
+            format!("{binary_0}\n{}", tail("  split <;> first | exact selector_func_col_0_binary c row | simp"))
+        );
+
+        // The selector is declared but never enabled, so selector_func is the constant 0 and has no match to split
+        let circuit = UnfoldingCircuit { selector_rows: vec![], fixed: vec![0] };
+        let (cs, extraction) = ExtractingAssignment::synthesize_circuit(&circuit, &ExtractionOptions::default()).unwrap();
+        assert_eq!(cs.num_selectors(), 1);
+        assert_eq!(extraction.selector_binary(), tail("  simp"));
+    }
+
+    #[test]
+    fn test_finset_lookup_tables() {
+        let circuit = LookupCircuit { table: vec![0, 1, 2, 1], inputs: 4 };
+        let (cs, mut extraction) = ExtractingAssignment::synthesize_circuit(&circuit, &ExtractionOptions::default()).unwrap();
+        let if_chain = BTreeMap::from([(0, vec![])]);
+        let entries = |entries: &[(&str, usize)]| entries.iter().map(|(entry, row)| (entry.to_string(), *row)).collect_vec();
+        // Each distinct row of the table is kept with the first row it occurs at
+        assert_eq!(extraction.finset_lookup_tables(&cs, &if_chain), vec![(0, entries(&[("(0)", 0), ("(1)", 1), ("(2)", 2)]))]);
+        // The lemmas unfold if-chains, so tables in array backed columns are left out
+        assert_eq!(extraction.finset_lookup_tables(&cs, &BTreeMap::new()), vec![]);
+
+        // The table at the next row is not the table at the lookup row
+        let mut rotated = ConstraintSystem::default();
+        configure_lookup(&mut rotated, Rotation::next());
+        assert_eq!(extraction.finset_lookup_tables(&rotated, &if_chain), vec![]);
+
+        // A symbolic value refers to c, which the Finset def does not take
+        extraction.fixed.get_mut(&0).unwrap().insert(1, "c.1.sym_offset".to_string());
+        assert_eq!(extraction.finset_lookup_tables(&cs, &if_chain), vec![]);
+
+        // The inputs use two more rows than the table, at which the table cells are unassigned and so arbitrary
+        let circuit = LookupCircuit { table: vec![0, 1, 2, 1], inputs: 6 };
+        let (cs, extraction) = ExtractingAssignment::synthesize_circuit(&circuit, &ExtractionOptions::default()).unwrap();
+        assert_eq!(extraction.usable_rows(), 6);
+        assert_eq!(extraction.finset_lookup_tables(&cs, &if_chain), vec![]);
+    }
+
+    // The preamble for a circuit with one advice column and a symbol named offset, with {minimum_rows} for cs.minimum_rows()
+    const PREAMBLE_ZMOD: &str = r"import Mathlib.Data.Nat.Prime.Defs
+import Mathlib.Data.Nat.Prime.Basic
+import Mathlib.Data.ZMod.Defs
+import Mathlib.GroupTheory.OrderOfElement
+import Mathlib.Data.ZMod.Basic
+
+set_option linter.unusedVariables false
+
+namespace Golden
+
+def S_T_from_P (S T P : ℕ) : Prop :=
+  (2^S * T = P - 1) ∧
+  (∀ s' t': ℕ, 2^s' * t' = P - 1 → s' ≤ S)
+def multiplicative_generator (P: ℕ) (mult_gen: ZMod P) : Prop :=
+  orderOf mult_gen = P - 1
+structure Circuit (P: ℕ) (P_Prime: Nat.Prime P) :=
+  Advice: ℕ → ℕ → ZMod P
+  AdviceUnassigned: ℕ → ℕ → ZMod P
+  AdvicePhase: ℕ → ℕ
+  Fixed: ℕ → ℕ → ZMod P
+  FixedUnassigned: ℕ → ℕ → ZMod P
+  Instance: ℕ → ℕ → ZMod P
+  InstanceUnassigned: ℕ → ℕ → ZMod P
+  Selector: ℕ → ℕ → ZMod P
+  Challenges: (ℕ → ℕ → ZMod P) → ℕ → ℕ → ZMod P
+  num_blinding_factors: ℕ
+  S: ℕ
+  T: ℕ
+  k: ℕ
+  mult_gen: ZMod P
+  sym_offset: ZMod P
+variable {P: ℕ} {P_Prime: Nat.Prime P}
+def Circuit.isValid (c: Circuit P P_Prime) : Prop :=
+  S_T_from_P c.S c.T P ∧
+  multiplicative_generator P c.mult_gen ∧ (
+  ∀ advice1 advice2: ℕ → ℕ → ZMod P, ∀ phase: ℕ,
+    (∀ row col, (col < 1 ∧ c.AdvicePhase col ≤ phase) → advice1 col row = advice2 col row) →
+    (∀ i, c.Challenges advice1 i phase = c.Challenges advice2 i phase)
+  )
+abbrev ValidCircuit (P: ℕ) (P_Prime: Nat.Prime P) : Type := {c: Circuit P P_Prime // c.isValid}
+namespace ValidCircuit
+def get_advice (c: ValidCircuit P P_Prime) : ℕ → ℕ → ZMod P :=
+  λ col row => c.1.Advice col row
+def get_fixed (c: ValidCircuit P P_Prime) : ℕ → ℕ → ZMod P :=
+  λ col row => c.1.Fixed col row
+def get_instance (c: ValidCircuit P P_Prime) : ℕ → ℕ → ZMod P :=
+  λ col row => c.1.Instance col row
+def get_selector (c: ValidCircuit P P_Prime) : ℕ → ℕ → ZMod P :=
+  λ col row => c.1.Selector col row
+def get_challenge (c: ValidCircuit P P_Prime) : ℕ → ℕ → ZMod P :=
+  λ idx phase => c.1.Challenges c.1.Advice idx phase
+def k (c: ValidCircuit P P_Prime) := c.1.k
+def n (c: ValidCircuit P P_Prime) := 2^c.k
+def usable_rows (c: ValidCircuit P P_Prime) := c.n - (c.1.num_blinding_factors + 1)
+def S (c: ValidCircuit P P_Prime) := c.1.S
+def T (c: ValidCircuit P P_Prime) := c.1.T
+def mult_gen (c: ValidCircuit P P_Prime) := c.1.mult_gen
+def root_of_unity (c: ValidCircuit P P_Prime) : ZMod P := c.mult_gen ^ c.T
+def delta (c: ValidCircuit P P_Prime) : ZMod P := c.mult_gen ^ (2^c.S)
+def two_inv (c: ValidCircuit P P_Prime) : ZMod P := 2⁻¹
+def inv (c: ValidCircuit P P_Prime) (x: ZMod P) : ZMod P := x⁻¹
+lemma root_of_unity_order (c: ValidCircuit P P_Prime) : orderOf c.root_of_unity = 2 ^ c.S := by
+  have h := c.2
+  unfold Circuit.isValid S_T_from_P multiplicative_generator at h
+  obtain ⟨⟨h_ST, _⟩, h_gen, _⟩ := h
+  simp only [root_of_unity, mult_gen, T, S]
+  have h_T : c.1.T ≠ 0 := by
+    rintro h_zero
+    rw [h_zero, mul_zero] at h_ST
+    have := P_Prime.two_le
+    omega
+  rw [orderOf_pow_of_dvd h_T (by rw [h_gen, ← h_ST]; exact Dvd.intro_left _ rfl), h_gen, ← h_ST]
+  exact Nat.mul_div_cancel _ (Nat.pos_of_ne_zero h_T)
+end ValidCircuit
+def is_shuffle (c: ValidCircuit P P_Prime) (shuffle: ℕ → ℕ): Prop :=
+  ∃ inv: ℕ → ℕ,
+  ∀ row: ℕ,
+    inv (shuffle row) = row ∧
+    (row ≥ c.usable_rows → shuffle row = row)
+def sufficient_rows (c: ValidCircuit P P_Prime) : Prop :=
+  c.n ≥ {minimum_rows} --cs.minimum_rows
+--End preamble
+";
+
+    const PREAMBLE_GENERIC: &str = r"import Mathlib.Data.Nat.Prime.Defs
+import Mathlib.Data.Nat.Prime.Basic
+import Mathlib.Data.ZMod.Defs
+import Mathlib.GroupTheory.OrderOfElement
+import Mathlib.Algebra.Field.Basic
+import Mathlib.Data.Fintype.Card
+import Mathlib.Data.ZMod.Basic
+
+set_option linter.unusedVariables false
+
+namespace Golden
+
+def S_T_from_P (S T P : ℕ) : Prop :=
+  (2^S * T = P - 1) ∧
+  (∀ s' t': ℕ, 2^s' * t' = P - 1 → s' ≤ S)
+def multiplicative_generator (F: Type) [Field F] [Fintype F] (mult_gen: F) : Prop :=
+  orderOf mult_gen = Fintype.card F - 1
+structure Circuit (F: Type) [Field F] [Fintype F] :=
+  Advice: ℕ → ℕ → F
+  AdviceUnassigned: ℕ → ℕ → F
+  AdvicePhase: ℕ → ℕ
+  Fixed: ℕ → ℕ → F
+  FixedUnassigned: ℕ → ℕ → F
+  Instance: ℕ → ℕ → F
+  InstanceUnassigned: ℕ → ℕ → F
+  Selector: ℕ → ℕ → F
+  Challenges: (ℕ → ℕ → F) → ℕ → ℕ → F
+  num_blinding_factors: ℕ
+  S: ℕ
+  T: ℕ
+  k: ℕ
+  mult_gen: F
+  sym_offset: F
+variable {F: Type} [Field F] [Fintype F] [DecidableEq F]
+def Circuit.isValid (c: Circuit F) : Prop :=
+  S_T_from_P c.S c.T (Fintype.card F) ∧
+  multiplicative_generator F c.mult_gen ∧ (
+  ∀ advice1 advice2: ℕ → ℕ → F, ∀ phase: ℕ,
+    (∀ row col, (col < 1 ∧ c.AdvicePhase col ≤ phase) → advice1 col row = advice2 col row) →
+    (∀ i, c.Challenges advice1 i phase = c.Challenges advice2 i phase)
+  )
+abbrev ValidCircuit (F: Type) [Field F] [Fintype F] : Type := {c: Circuit F // c.isValid}
+namespace ValidCircuit
+def get_advice (c: ValidCircuit F) : ℕ → ℕ → F :=
+  λ col row => c.1.Advice col row
+def get_fixed (c: ValidCircuit F) : ℕ → ℕ → F :=
+  λ col row => c.1.Fixed col row
+def get_instance (c: ValidCircuit F) : ℕ → ℕ → F :=
+  λ col row => c.1.Instance col row
+def get_selector (c: ValidCircuit F) : ℕ → ℕ → F :=
+  λ col row => c.1.Selector col row
+def get_challenge (c: ValidCircuit F) : ℕ → ℕ → F :=
+  λ idx phase => c.1.Challenges c.1.Advice idx phase
+def k (c: ValidCircuit F) := c.1.k
+def n (c: ValidCircuit F) := 2^c.k
+def usable_rows (c: ValidCircuit F) := c.n - (c.1.num_blinding_factors + 1)
+def S (c: ValidCircuit F) := c.1.S
+def T (c: ValidCircuit F) := c.1.T
+def mult_gen (c: ValidCircuit F) := c.1.mult_gen
+def root_of_unity (c: ValidCircuit F) : F := c.mult_gen ^ c.T
+def delta (c: ValidCircuit F) : F := c.mult_gen ^ (2^c.S)
+def two_inv (c: ValidCircuit F) : F := 2⁻¹
+def inv (c: ValidCircuit F) (x: F) : F := x⁻¹
+lemma root_of_unity_order (c: ValidCircuit F) : orderOf c.root_of_unity = 2 ^ c.S := by
+  have h := c.2
+  unfold Circuit.isValid S_T_from_P multiplicative_generator at h
+  obtain ⟨⟨h_ST, _⟩, h_gen, _⟩ := h
+  simp only [root_of_unity, mult_gen, T, S]
+  have h_T : c.1.T ≠ 0 := by
+    rintro h_zero
+    rw [h_zero, mul_zero] at h_ST
+    have := Fintype.one_lt_card (α := F)
+    omega
+  rw [orderOf_pow_of_dvd h_T (by rw [h_gen, ← h_ST]; exact Dvd.intro_left _ rfl), h_gen, ← h_ST]
+  exact Nat.mul_div_cancel _ (Nat.pos_of_ne_zero h_T)
+end ValidCircuit
+-- ZMod P for a prime P is one instance of the fields the model is over
+example (P: ℕ) [Fact P.Prime] [NeZero P] (c: ValidCircuit (ZMod P)) : ZMod P := c.delta
+def is_shuffle (c: ValidCircuit F) (shuffle: ℕ → ℕ): Prop :=
+  ∃ inv: ℕ → ℕ,
+  ∀ row: ℕ,
+    inv (shuffle row) = row ∧
+    (row ≥ c.usable_rows → shuffle row = row)
+def sufficient_rows (c: ValidCircuit F) : Prop :=
+  c.n ≥ {minimum_rows} --cs.minimum_rows
+--End preamble
+";
+
+    #[test]
+    fn test_preamble() {
+        let mut cs = ConstraintSystem::<TermField>::default();
+        cs.advice_column();
+        for (field_mode, expected) in [(FieldMode::ZMod, PREAMBLE_ZMOD), (FieldMode::Generic, PREAMBLE_GENERIC)] {
+            let options = ExtractionOptions { field_mode, ..ExtractionOptions::default() };
+            let expected = expected.replace("{minimum_rows}", &cs.minimum_rows().to_string());
+            assert_eq!(preamble("Golden", &["offset"], &cs, &options), expected, "{field_mode:?}");
+        }
+    }
+
+    #[test]
+    fn test_postamble() {
+        let cs = ConstraintSystem::<TermField>::default();
+        let options = ExtractionOptions { field_mode: FieldMode::Generic, ..ExtractionOptions::default() };
+        let postamble = postamble("Golden", &cs, 7, &options);
+        // The usable rows come from the argument rather than a file
+        assert!(postamble.contains("  c.usable_rows ≥ 7 ∧\n"), "{postamble}");
+        // Over a generic field, the postamble checks that meets_constraints applies to circuits over ZMod P
+        assert!(postamble.contains("(c: ValidCircuit (ZMod P)) : Prop := meets_constraints c\nend Golden\n"), "{postamble}");
+    }
+
+    #[test]
+    fn test_min_k() {
+        // With two used rows 2^k - (blinding factors + 1) ≥ 2 is the same condition as 2^k ≥ cs.minimum_rows
+        let (cs, extraction) = ExtractingAssignment::synthesize_circuit(&RowsCircuit { rows: 2 }, &ExtractionOptions::default()).unwrap();
+        assert_eq!(cs.minimum_rows(), cs.blinding_factors() + 3);
+        let min_k = extraction.min_k(&cs);
+        assert_eq!(1 << min_k, cs.minimum_rows().next_power_of_two());
+        assert!(extraction.fits_k(&cs, min_k));
+        assert!(!extraction.fits_k(&cs, min_k - 1));
+
+        // 100 used rows and the blinding rows fit in 128 rows but not in 64
+        let (cs, extraction) = ExtractingAssignment::synthesize_circuit(&RowsCircuit { rows: 100 }, &ExtractionOptions::default()).unwrap();
+        assert_eq!(extraction.usable_rows(), 100);
+        assert_eq!(extraction.min_k(&cs), 7);
+        assert!(extraction.fits_k(&cs, 8));
+        assert!(!extraction.fits_k(&cs, 6));
+        // 2^64 rows cannot be counted in a usize, so they are no fit rather than an overflow
+        assert!(extraction.fits_k(&cs, usize::BITS - 1));
+        assert!(!extraction.fits_k(&cs, usize::BITS));
+        assert!(!extraction.fits_k(&cs, u32::MAX));
+    }
+
+    #[test]
+    fn test_fixed_encoding() {
+        let extract = |fixed_encoding| {
+            let options = ExtractionOptions { fixed_encoding, ..ExtractionOptions::default() };
+            ExtractingAssignment::synthesize_circuit(&RowsCircuit { rows: MIN_ARRAY_RUNS + 1 }, &options).unwrap().1
+        };
+        let mut extractions = [FixedEncoding::Auto, FixedEncoding::IfChain, FixedEncoding::Array].map(extract);
+        assert_eq!(extractions.each_ref().map(|extraction| extraction.fixed_is_array(0)), [true, false, true]);
+
+        // A symbolic value refers to the circuit, which the array def does not take, so no encoding makes it an array
+        for extraction in &mut extractions {
+            extraction.fixed.get_mut(&0).unwrap().insert(1, "c.1.sym_offset".to_string());
+            assert!(!extraction.fixed_is_array(0));
+        }
+    }
+
+    #[test]
+    fn test_symbols_per_extraction() {
+        let circuit = SymbolCircuit { name: "offset" };
+        let (_, first) = ExtractingAssignment::synthesize_circuit(&circuit, &ExtractionOptions::default()).unwrap();
+        let (_, second) = ExtractingAssignment::synthesize_circuit(&circuit, &ExtractionOptions::default()).unwrap();
+        // unused is created but no term refers to it, so it is not declared
+        assert_eq!(first.symbols, vec!["offset", "unknown_fixed_0_1_0"]);
+        // Neither the symbols of the first extraction nor its fresh symbol numbering carry over
+        assert_eq!(second.symbols, first.symbols);
+        assert_eq!(second.fixed_value(0, 1).map(String::as_str), Some("c.1.sym_unknown_fixed_0_1_0"));
+    }
+
+    #[test]
+    fn test_concurrent_extractions() {
+        // Symbols created by the circuit on one thread never reach the extraction on the other
+        let threads = ["offset", "scale"].map(|name| {
+            std::thread::spawn(move || {
+                for _ in 0..20 {
+                    let (_, extraction) = ExtractingAssignment::synthesize_circuit(&SymbolCircuit { name }, &ExtractionOptions::default()).unwrap();
+                    assert_eq!(extraction.symbols, vec![name, "unknown_fixed_0_1_0"]);
+                }
+            })
+        });
+        for thread in threads {
+            thread.join().unwrap();
+        }
     }
 }
@@ -2,13 +2,33 @@ use std::{
     fmt::{Debug, Display}, iter::{Product, Sum}, ops::{Add, AddAssign, Mul, MulAssign, Neg, Sub, SubAssign}
 };
 
-use arrayvec::ArrayString;
+use std::cell::RefCell;
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+
 use ff::{Field, FromUniformBytes, PrimeField};
 use num_bigint::BigUint;
+use num_traits::Num;
 use subtle::{Choice, ConditionallySelectable, ConstantTimeEq, CtOption};
 
+use crate::printer::{BinaryOp, LeanTerm};
+
 const EXPRESSION_MAX_SIZE: usize = 16384;
 
+// Numbers the fresh symbols created outside of any extraction session
+static NEXT_SESSIONLESS_SYMBOL: AtomicUsize = AtomicUsize::new(0);
+
+// The names of the symbols a text refers to, such as offset in c.1.sym_offset + 1, in order of appearance
+pub fn symbols_in(text: &str) -> Vec<&str> {
+    text.match_indices("c.1.sym_")
+        .map(|(start, prefix)| {
+            let rest = &text[start + prefix.len()..];
+            let end = rest.find(|ch: char| !(ch.is_alphanumeric() || ch == '_' || ch == '\'')).unwrap_or(rest.len());
+            &rest[..end]
+        })
+        .collect()
+}
+
 // Field requires Copy, Sized, and 'static
 // This means we have to use a stack allocated, dynamically generatable, fixed length string
 #[derive(Clone, Copy)]
@@ -97,8 +117,36 @@ impl TermField {
         TermField::TwoInv
     }
 
-    // Note that this requires the symbol also be passed to print_preamble
+    // The extraction declares every symbol its terms refer to in the Circuit structure
+    // Creating a symbol with the same name twice refers to the same value
     pub fn create_symbol(name: &str) -> Self {
+        with_session(|session| {
+            if let Some(session) = session {
+                if !session.names.iter().any(|symbol| symbol == name) {
+                    session.names.push(name.to_string());
+                }
+            }
+        });
+        TermField::from(format!("c.1.sym_{name}"))
+    }
+
+    // Creates a symbol with a name the session has not used yet, of the form {prefix}_{n}
+    // Outside of a session the names are of the form {prefix}_x{n}, numbered process wide, so that they never meet those of a session
+    pub fn fresh_symbol(prefix: &str) -> Self {
+        let name = with_session(|session| match session {
+            Some(session) => {
+                let name = loop {
+                    let name = format!("{prefix}_{}", session.next_fresh_symbol);
+                    session.next_fresh_symbol += 1;
+                    if !session.names.contains(&name) {
+                        break name;
+                    }
+                };
+                session.names.push(name.clone());
+                name
+            },
+            None => format!("{prefix}_x{}", NEXT_SESSIONLESS_SYMBOL.fetch_add(1, AtomicOrdering::SeqCst)),
+        });
         TermField::from(format!("c.1.sym_{name}"))
     }
 
@@ -110,11 +158,11 @@ impl TermField {
                     (**x).clone()
                 }
             }
-            TermField::TwoInv => String::from("(2: ZMod P).inv"),
+            TermField::TwoInv => String::from("c.two_inv"),
             TermField::MultiplicativeGenerator => String::from("c.mult_gen"),
             TermField::S => String::from("c.S"),
             TermField::RootOfUnity => String::from("c.root_of_unity"),
-            TermField::RootOfUnityInv => String::from("c.root_of_unity.inv"),
+            TermField::RootOfUnityInv => String::from("c.root_of_unity⁻¹"),
             TermField::Delta => String::from("c.delta"),
         }
     }
@@ -122,6 +170,37 @@ impl TermField {
     pub fn create_s() -> Self {
         Self::from("S")
     }
+
+    // The value of this term in a concrete field, or None if it depends on a symbol
+    // Constants that went through to_string are recognised by their Lean text
+    pub fn to_concrete<F: PrimeField>(&self) -> Option<F> {
+        let text = match self {
+            TermField::Val(x) => {
+                let magnitude = F::from(x.unsigned_abs());
+                return Some(if *x < 0 { -magnitude } else { magnitude });
+            },
+            _ => self.to_expr(),
+        };
+        match text.as_str() {
+            "c.two_inv" => Some(F::TWO_INV),
+            "c.mult_gen" => Some(F::MULTIPLICATIVE_GENERATOR),
+            "c.S" => Some(F::from(F::S as u64)),
+            "c.root_of_unity" => Some(F::ROOT_OF_UNITY),
+            "c.root_of_unity⁻¹" => Some(F::ROOT_OF_UNITY_INV),
+            "c.delta" => Some(F::DELTA),
+            text => {
+                let (negative, digits) = match text.strip_prefix('-') {
+                    Some(digits) => (true, digits),
+                    None => (false, text),
+                };
+                if digits.is_empty() || !digits.chars().all(|ch| ch.is_ascii_digit()) {
+                    return None;
+                }
+                let magnitude = F::from_str_vartime(digits)?;
+                Some(if negative { -magnitude } else { magnitude })
+            },
+        }
+    }
 }
 
 impl From<&str> for TermField {
@@ -185,7 +264,7 @@ impl Neg for TermField {
         match self {
             // TODO checks
             Self::Val(x) => Self::Val(-x),
-            _ => Self::from(&format!("-({})", self.to_expr())),
+            _ => Self::from(LeanTerm::neg(LeanTerm::text(self.to_expr())).to_string()),
         }
     }
 }
@@ -207,7 +286,7 @@ impl Add for TermField {
             },
             (Self::Val(0), _) => rhs,
             (_, Self::Val(0)) => self,
-            _ => Self::from(&format!("({}) + ({})", self.to_expr(), rhs.to_expr())),
+            _ => Self::from(LeanTerm::binary(BinaryOp::Add, LeanTerm::text(self.to_expr()), LeanTerm::text(rhs.to_expr())).to_string()),
         }
     }
 }
@@ -220,7 +299,7 @@ impl Sub for TermField {
             (Self::Val(x), Self::Val(y)) => Self::Val(x-y),
             (Self::Val(0), _) => rhs.neg(),
             (_, Self::Val(0)) => self,
-            _ => Self::from(&format!("({}) - ({})", self.to_expr(), rhs.to_expr())),
+            _ => Self::from(LeanTerm::binary(BinaryOp::Sub, LeanTerm::text(self.to_expr()), LeanTerm::text(rhs.to_expr())).to_string()),
         }
     }
 }
@@ -242,7 +321,7 @@ impl Mul for TermField {
             },
             (Self::Val(1), _) => rhs,
             (_, Self::Val(1)) => self,
-            _ => Self::from(&format!("({}) * ({})", self.to_expr(), rhs.to_expr())),
+            _ => Self::from(LeanTerm::binary(BinaryOp::Mul, LeanTerm::text(self.to_expr()), LeanTerm::text(rhs.to_expr())).to_string()),
         }
     }
 }
@@ -355,8 +434,9 @@ impl Field for TermField {
     const ZERO: Self = Self::zero();
     const ONE: Self = Self::one();
 
+    // A random value is modelled as an unconstrained symbol
     fn random(_rng: impl rand_core::RngCore) -> Self {
-        panic!("Random with a random number generator is not supported. Use create_symbol for an unknown value")
+        Self::fresh_symbol("random")
     }
 
     fn square(&self) -> Self {
@@ -373,7 +453,7 @@ impl Field for TermField {
 
         #[cfg(feature = "unsafe-invert")]
         CtOption::new(
-            Self::from(format!("(({}: ZMod P).inv)", self)),
+            Self::from(format!("(c.inv ({}))", self)),
             Choice::from(1),
         )
     }
@@ -403,57 +483,230 @@ impl From<u64> for TermField {
     }
 }
 
+// The constants of a concrete prime field in decimal, so that the emitted model can pin down
+// the values the circuit only refers to symbolically, such as TermField::Delta
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FieldProfile {
+    pub name: String,
+    pub modulus: String,
+    pub s: u32,
+    pub t: String,
+    pub two_inv: String,
+    pub mult_gen: String,
+    pub root_of_unity: String,
+    pub root_of_unity_inv: String,
+    pub delta: String,
+}
+
+impl FieldProfile {
+    // Reads the constants halo2 uses for F, which must have a hex MODULUS and a little-endian repr, as halo2curves fields do
+    pub fn of<F: PrimeField>(name: &str) -> Self {
+        let decimal = |value: F| BigUint::from_bytes_le(value.to_repr().as_ref()).to_str_radix(10);
+        let modulus = BigUint::from_str_radix(F::MODULUS.trim_start_matches("0x"), 16).expect("PrimeField::MODULUS is not hex");
+        let t = (&modulus - 1u32) >> F::S;
+        Self {
+            name: name.to_string(),
+            modulus: modulus.to_str_radix(10),
+            s: F::S,
+            t: t.to_str_radix(10),
+            two_inv: decimal(F::TWO_INV),
+            mult_gen: decimal(F::MULTIPLICATIVE_GENERATOR),
+            root_of_unity: decimal(F::ROOT_OF_UNITY),
+            root_of_unity_inv: decimal(F::ROOT_OF_UNITY_INV),
+            delta: decimal(F::DELTA),
+        }
+    }
+
+    pub fn bn256_fr() -> Self {
+        Self::of::<halo2curves::bn256::Fr>("bn256::Fr")
+    }
+
+    pub fn pasta_fp() -> Self {
+        Self::of::<halo2curves::pasta::Fp>("pasta::Fp")
+    }
+
+    pub fn pasta_fq() -> Self {
+        Self::of::<halo2curves::pasta::Fq>("pasta::Fq")
+    }
+}
+
+// The number of bytes exposed by a numeric repr, matching the 256-bit fields halo2 circuits are usually written against
+const NUMBER_REPR_SIZE: usize = 32;
+
+/// How `PrimeField::to_repr` encodes a `TermField`, and how `PrimeField::from_repr` decodes it.
+/// Each `ExtractionSession` sets it for its own thread, outside of a session it is the default.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ReprMode {
+    /// Every value is encoded as the UTF-8 text of its Lean expression
+    #[default]
+    Text,
+    /// Non-negative numeric values are encoded as little-endian bytes, symbolic values fall back to text
+    Number,
+}
+
+// The settings of one extraction, which TermField reads as its methods have no access to the extraction
+struct SessionState {
+    repr_mode: ReprMode,
+    // Symbols created during the session, which fresh symbols must not reuse
+    names: Vec<String>,
+    next_fresh_symbol: usize,
+}
+
+thread_local! {
+    // The sessions begun on this thread and not yet ended, innermost last
+    // Circuits that synthesize on other threads, such as rayon workers, see no session there
+    static SESSIONS: RefCell<Vec<SessionState>> = const { RefCell::new(Vec::new()) };
+}
+
+// Runs f with the innermost session of this thread, if there is one
+fn with_session<R>(f: impl FnOnce(Option<&mut SessionState>) -> R) -> R {
+    SESSIONS.with(|sessions| f(sessions.borrow_mut().last_mut()))
+}
+
+pub fn repr_mode() -> ReprMode {
+    with_session(|session| session.map_or(ReprMode::default(), |session| session.repr_mode))
+}
+
+// Sets the repr mode and numbers fresh symbols for one extraction on this thread, until it is dropped
+// Sessions nest, so a sub-circuit can be extracted while another circuit is synthesized,
+// and sessions on different threads are independent
+pub struct ExtractionSession {
+    // The number of sessions this one is nested in
+    depth: usize,
+    // Sessions belong to the thread that began them
+    _not_send: PhantomData<*const ()>,
+}
+
+impl ExtractionSession {
+    // Fresh symbols are numbered from 0 in every session, so that extracting the same circuit twice gives the same names
+    pub fn begin(mode: ReprMode) -> Self {
+        let depth = SESSIONS.with(|sessions| {
+            let mut sessions = sessions.borrow_mut();
+            sessions.push(SessionState { repr_mode: mode, names: vec![], next_fresh_symbol: 0 });
+            sessions.len() - 1
+        });
+        Self { depth, _not_send: PhantomData }
+    }
+}
+
+impl Drop for ExtractionSession {
+    // Restores the settings of the session this one is nested in, or the defaults
+    fn drop(&mut self) {
+        let depth = SESSIONS.with(|sessions| {
+            let mut sessions = sessions.borrow_mut();
+            sessions.pop();
+            sessions.len()
+        });
+        if depth != self.depth && !std::thread::panicking() {
+            panic!("Extraction sessions must end in the reverse order they began");
+        }
+    }
+}
+
 #[derive(Clone, Copy)]
-pub struct TermFieldBytes (ArrayString<EXPRESSION_MAX_SIZE>);
+pub struct TermFieldBytes {
+    bytes: [u8; EXPRESSION_MAX_SIZE],
+    // Number of meaningful bytes, for numbers this is never less than NUMBER_REPR_SIZE
+    len: usize,
+    // Whether bytes holds little-endian digits or the text of an expression
+    // This is recorded per value so that symbolic values survive a round trip in ReprMode::Number
+    mode: ReprMode,
+}
+
+impl TermFieldBytes {
+    fn from_text(text: &str) -> Self {
+        let text_bytes = text.as_bytes();
+        assert!(
+            text_bytes.len() <= EXPRESSION_MAX_SIZE,
+            "Expression of {} bytes is too long to be stored in a TermFieldBytes (maximum {EXPRESSION_MAX_SIZE})",
+            text_bytes.len()
+        );
+        let mut bytes = [0; EXPRESSION_MAX_SIZE];
+        bytes[..text_bytes.len()].copy_from_slice(text_bytes);
+        Self {
+            bytes,
+            len: text_bytes.len(),
+            mode: ReprMode::Text,
+        }
+    }
+
+    fn from_number(value: &BigUint) -> Self {
+        let digits = value.to_bytes_le();
+        assert!(
+            digits.len() <= EXPRESSION_MAX_SIZE,
+            "Value of {} bytes is too large to be stored in a TermFieldBytes (maximum {EXPRESSION_MAX_SIZE})",
+            digits.len()
+        );
+        let mut bytes = [0; EXPRESSION_MAX_SIZE];
+        bytes[..digits.len()].copy_from_slice(&digits);
+        Self {
+            bytes,
+            len: digits.len().max(NUMBER_REPR_SIZE),
+            mode: ReprMode::Number,
+        }
+    }
+
+    // Text written through as_mut does not update len, so it is terminated by the first zero byte instead
+    fn text(&self) -> &str {
+        let end = self.bytes.iter().position(|&b| b == 0).unwrap_or(EXPRESSION_MAX_SIZE);
+        std::str::from_utf8(&self.bytes[..end]).expect("TermFieldBytes text is not valid UTF-8")
+    }
+}
 
 impl Default for TermFieldBytes {
     fn default() -> Self {
-        Self(ArrayString::<EXPRESSION_MAX_SIZE>::default())
+        let mode = repr_mode();
+        Self {
+            bytes: [0; EXPRESSION_MAX_SIZE],
+            len: match mode {
+                ReprMode::Text => 0,
+                ReprMode::Number => NUMBER_REPR_SIZE,
+            },
+            mode,
+        }
     }
 }
 
 impl AsMut<[u8]> for TermFieldBytes {
     fn as_mut(&mut self) -> &mut [u8] {
-        todo!()
+        match self.mode {
+            ReprMode::Number => &mut self.bytes[..self.len],
+            // Expose the whole buffer so that callers can write text of any length
+            ReprMode::Text => &mut self.bytes,
+        }
     }
 }
 
 impl AsRef<[u8]> for TermFieldBytes {
     fn as_ref(&self) -> &[u8] {
-        self.0.as_str().as_bytes()
+        match self.mode {
+            ReprMode::Number => &self.bytes[..self.len],
+            ReprMode::Text => self.text().as_bytes(),
+        }
     }
 }
 
 impl PrimeField for TermField {
     type Repr = TermFieldBytes;
 
-    #[cfg(all(feature = "repr-text", feature = "repr-number"))]
-    fn from_repr(_repr: Self::Repr) -> CtOption<Self> {
-        compile_error!("features `halo2-extractor/repr-text` and `halo2-extractor/repr-number` are mutually exclusive");
-    }
-
-    #[cfg(not(any(feature = "repr-text", feature = "repr-number")))]
-    fn from_repr(_repr: Self::Repr) -> CtOption<Self> {        
-        panic!("from_repr requires either the repr-text flag or the repr-number feature to be enabled");
-    }
-
-    #[cfg(all(feature = "repr-number", not(feature = "repr-text")))]
     fn from_repr(repr: Self::Repr) -> CtOption<Self> {
-        let x = BigUint::from_bytes_le(&repr.0.as_bytes());
-        CtOption::new(Self::from(x.to_str_radix(10)), Choice::from(1))
-    }
-
-    #[cfg(all(feature = "repr-text", not(feature = "repr-number")))]
-    fn from_repr(repr: Self::Repr) -> CtOption<Self> {
-        CtOption::new(Self::Expr(repr.0), Choice::from(1))
+        let value = match repr.mode {
+            ReprMode::Number => Self::from(BigUint::from_bytes_le(repr.as_ref()).to_str_radix(10)),
+            ReprMode::Text => Self::from(repr.text()),
+        };
+        CtOption::new(value, Choice::from(1))
     }
 
     fn to_repr(&self) -> Self::Repr {
-        #[cfg(not(feature = "repr-text"))]
-        unimplemented!("to_repr requires the repr-text feature");
-        
-        #[cfg(feature = "repr-text")]
-        TermFieldBytes(self.to_expr())
+        let expr = self.to_expr();
+        match repr_mode() {
+            ReprMode::Text => TermFieldBytes::from_text(&expr),
+            // Negative values have no canonical representation without knowing the modulus, so they are kept symbolic
+            ReprMode::Number => match BigUint::from_str_radix(&expr, 10) {
+                Ok(value) => TermFieldBytes::from_number(&value),
+                Err(_) => TermFieldBytes::from_text(&expr),
+            },
+        }
     }
 
     fn is_odd(&self) -> Choice {
@@ -504,3 +757,97 @@ impl FromUniformBytes<64> for TermField {
 //         todo!()
 //     }
 // }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_repr_round_trip() {
+        let values = [
+            TermField::from(5u64),
+            TermField::from("123456789012345678901234567890"),
+            TermField::from("c.1.sym_a"),
+            -TermField::from(3u64),
+        ];
+        for mode in [ReprMode::Text, ReprMode::Number] {
+            let _session = ExtractionSession::begin(mode);
+            for value in values {
+                let round_trip = TermField::from_repr(value.to_repr()).unwrap();
+                assert_eq!(round_trip.to_expr(), value.to_expr());
+            }
+        }
+    }
+
+    #[test]
+    fn test_concurrent_sessions() {
+        let threads = [ReprMode::Text, ReprMode::Number].map(|mode| {
+            std::thread::spawn(move || {
+                for _ in 0..100 {
+                    let _session = ExtractionSession::begin(mode);
+                    assert_eq!(repr_mode(), mode);
+                    assert_eq!(TermField::fresh_symbol("random").to_expr(), "c.1.sym_random_0");
+                    std::thread::yield_now();
+                    assert_eq!(repr_mode(), mode);
+                    assert_eq!(TermField::fresh_symbol("random").to_expr(), "c.1.sym_random_1");
+                }
+            })
+        });
+        for thread in threads {
+            thread.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_nested_sessions() {
+        assert_eq!(repr_mode(), ReprMode::Text);
+        let outer = ExtractionSession::begin(ReprMode::Number);
+        assert_eq!(TermField::fresh_symbol("random").to_expr(), "c.1.sym_random_0");
+        {
+            let _inner = ExtractionSession::begin(ReprMode::Text);
+            assert_eq!(repr_mode(), ReprMode::Text);
+            assert_eq!(TermField::fresh_symbol("random").to_expr(), "c.1.sym_random_0");
+        }
+        // The outer session continues where it left off
+        assert_eq!(repr_mode(), ReprMode::Number);
+        assert_eq!(TermField::fresh_symbol("random").to_expr(), "c.1.sym_random_1");
+        drop(outer);
+        assert_eq!(repr_mode(), ReprMode::Text);
+    }
+
+    #[test]
+    fn test_symbols_in() {
+        assert_eq!(symbols_in("c.1.sym_offset * (c.1.sym_random_0 + c.1.sym_x') - 1"), vec!["offset", "random_0", "x'"]);
+        assert!(symbols_in("c.delta").is_empty());
+    }
+
+    // Checks the constants of the profile against each other as the preamble defines them,
+    // e.g. that delta is mult_gen^(2^S) and root_of_unity is mult_gen^T
+    fn check_profile(profile: &FieldProfile) {
+        let number = |value: &str| BigUint::from_str_radix(value, 10).unwrap();
+        let (modulus, t, mult_gen) = (number(&profile.modulus), number(&profile.t), number(&profile.mult_gen));
+        assert_eq!((&t << profile.s) + 1u32, modulus, "{}", profile.name);
+        assert_eq!(mult_gen.modpow(&t, &modulus), number(&profile.root_of_unity), "{}", profile.name);
+        assert_eq!(mult_gen.modpow(&(BigUint::from(1u32) << profile.s), &modulus), number(&profile.delta), "{}", profile.name);
+        assert_eq!(number(&profile.two_inv) * 2u32 % &modulus, BigUint::from(1u32), "{}", profile.name);
+        assert_eq!(number(&profile.root_of_unity) * number(&profile.root_of_unity_inv) % &modulus, BigUint::from(1u32), "{}", profile.name);
+    }
+
+    #[test]
+    fn test_bn256_profile() {
+        let profile = FieldProfile::bn256_fr();
+        assert_eq!(profile.modulus, "21888242871839275222246405745257275088548364400416034343698204186575808495617");
+        assert_eq!(profile.s, 28);
+        assert_eq!(profile.mult_gen, "7");
+        check_profile(&profile);
+    }
+
+    #[test]
+    fn test_pasta_profiles() {
+        for profile in [FieldProfile::pasta_fp(), FieldProfile::pasta_fq()] {
+            assert_eq!(profile.s, 32);
+            assert_eq!(profile.mult_gen, "5");
+            check_profile(&profile);
+        }
+    }
+}
@@ -0,0 +1,252 @@
+use plotters::coord::{types::RangedCoordusize, Shift};
+use plotters::prelude::*;
+use plotters::style::text_anchor::{HPos, Pos, VPos};
+
+use halo2_proofs::plonk::{Any, ConstraintSystem};
+
+use crate::extraction::ExtractingAssignment;
+use crate::field::TermField;
+
+// Cell contents are only written out for tables this small, beyond that they would be unreadable
+const MAX_TEXT_ROWS: usize = 64;
+const MAX_TEXT_LEN: usize = 12;
+const HEADER_HEIGHT: u32 = 120;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum LayoutColumn {
+    Instance(usize),
+    Advice(usize),
+    Fixed(usize),
+    Selector(usize),
+}
+
+impl ExtractingAssignment<TermField> {
+    // Draws the recorded layout of a table of 2^k rows, in the style of halo2's CircuitLayout
+    // Row 0 is at the top, and each column is labelled with its index and annotation
+    pub fn render_layout<DB: DrawingBackend>(
+        &self,
+        cs: &ConstraintSystem<TermField>,
+        k: u32,
+        drawing_area: &DrawingArea<DB, Shift>,
+    ) -> Result<(), DrawingAreaErrorKind<DB::ErrorType>> {
+        let n = 1usize << k;
+        let usable_rows = n.saturating_sub(cs.blinding_factors() + 1);
+        let columns = (0..cs.num_instance_columns()).map(LayoutColumn::Instance)
+            .chain((0..cs.num_advice_columns()).map(LayoutColumn::Advice))
+            .chain((0..cs.num_fixed_columns()).map(LayoutColumn::Fixed))
+            .chain((0..cs.num_selectors()).map(LayoutColumn::Selector))
+            .collect::<Vec<_>>();
+        let column_position = |column: LayoutColumn| columns.iter().position(|c| *c == column);
+
+        drawing_area.fill(&WHITE)?;
+        let (header, body) = drawing_area.split_vertically(HEADER_HEIGHT);
+
+        // Every cell is two units wide and tall, so that cell centres have integer coordinates
+        let root = body.apply_coord_spec(Cartesian2d::<RangedCoordusize, RangedCoordusize>::new(
+            0..2 * columns.len().max(1),
+            0..2 * n,
+            body.get_pixel_range(),
+        ));
+        let top = |row: usize| 2 * (n - row);
+        let cell = |x: usize, row: usize| [(2 * x, top(row)), (2 * x + 2, top(row) - 2)];
+        let centre = |x: usize, row: usize| (2 * x + 1, top(row) - 1);
+        let show_text = n <= MAX_TEXT_ROWS;
+        let text_style = ("sans-serif", 10).into_font().color(&BLACK);
+
+        // Column backgrounds
+        for (x, column) in columns.iter().enumerate() {
+            let colour = match column {
+                LayoutColumn::Instance(_) => WHITE.mix(1.0),
+                LayoutColumn::Advice(_) => RED.mix(0.1),
+                LayoutColumn::Fixed(_) => BLUE.mix(0.1),
+                LayoutColumn::Selector(_) => MAGENTA.mix(0.1),
+            };
+            root.draw(&Rectangle::new([(2 * x, 2 * n), (2 * x + 2, 0)], colour.filled()))?;
+        }
+
+        // Blinding rows and the boundary of the usable region
+        root.draw(&Rectangle::new([(0, top(usable_rows)), (2 * columns.len(), 0)], BLACK.mix(0.15).filled()))?;
+        root.draw(&PathElement::new(vec![(0, top(usable_rows)), (2 * columns.len(), top(usable_rows))], RED.stroke_width(2)))?;
+
+        // Assigned advice cells
+        for (col, (_, rows)) in &self.advice_column_annotations {
+            let Some(x) = column_position(LayoutColumn::Advice(*col)) else { continue };
+            for (row, annotation) in rows.iter().filter(|(row, _)| **row < n) {
+                root.draw(&Rectangle::new(cell(x, *row), RED.mix(0.5).filled()))?;
+                if show_text {
+                    root.draw(&Text::new(truncate(annotation), (2 * x, top(*row)), text_style.clone()))?;
+                }
+            }
+        }
+
+        // Assigned fixed cells, including fills
+        for col in 0..cs.num_fixed_columns() {
+            let Some(x) = column_position(LayoutColumn::Fixed(col)) else { continue };
+            for row in 0..usable_rows {
+                if let Some(value) = self.fixed_value(col, row) {
+                    root.draw(&Rectangle::new(cell(x, row), BLUE.mix(0.4).filled()))?;
+                    if show_text {
+                        root.draw(&Text::new(truncate(value), (2 * x, top(row)), text_style.clone()))?;
+                    }
+                }
+            }
+        }
+
+        // Enabled selectors
+        for (selector, rows) in &self.selectors {
+            let Some(x) = column_position(LayoutColumn::Selector(*selector)) else { continue };
+            for row in rows.keys().filter(|row| **row < n) {
+                root.draw(&Rectangle::new(cell(x, *row), MAGENTA.mix(0.6).filled()))?;
+            }
+        }
+
+        // Region boundaries, across the whole table as only their rows are recorded
+        for region in &self.regions {
+            let Some((start, end)) = region.rows else { continue };
+            if start >= n {
+                continue;
+            }
+            let end = end.min(n - 1);
+            root.draw(&Rectangle::new([(0, top(start)), (2 * columns.len(), top(end) - 2)], BLACK.stroke_width(2)))?;
+            root.draw(&Text::new(region.name.clone(), (0, top(start)), ("sans-serif", 12).into_font().color(&BLACK)))?;
+        }
+
+        // Copy constraints, bent to the right so that copies within one column are visible
+        for ((left_column, left_row), (right_column, right_row)) in &self.copies {
+            let position = |column: &halo2_proofs::plonk::Column<Any>| column_position(match column.column_type() {
+                Any::Advice => LayoutColumn::Advice(column.index()),
+                Any::Fixed => LayoutColumn::Fixed(column.index()),
+                Any::Instance => LayoutColumn::Instance(column.index()),
+            });
+            let (Some(left_x), Some(right_x)) = (position(left_column), position(right_column)) else { continue };
+            if *left_row >= n || *right_row >= n {
+                continue;
+            }
+            let (left, right) = (centre(left_x, *left_row), centre(right_x, *right_row));
+            let bend = (left.0.max(right.0) + 1, (left.1 + right.1) / 2);
+            root.draw(&PathElement::new(vec![left, bend, right], GREEN.stroke_width(1)))?;
+        }
+
+        // Column labels, written upwards from the bottom of the header
+        let (x_range, _) = body.get_pixel_range();
+        let column_width = (x_range.end - x_range.start) as f64 / columns.len().max(1) as f64;
+        let label_style = ("sans-serif", 12)
+            .into_font()
+            .transform(FontTransform::Rotate270)
+            .color(&BLACK)
+            .pos(Pos::new(HPos::Left, VPos::Center));
+        for (x, column) in columns.iter().enumerate() {
+            let (label, annotation) = match column {
+                LayoutColumn::Instance(col) => (format!("i{col}"), self.instance_column_annotations.get(col)),
+                LayoutColumn::Advice(col) => (format!("a{col}"), self.advice_column_annotations.get(col)),
+                LayoutColumn::Fixed(col) => (format!("f{col}"), self.fixed_column_annotations.get(col)),
+                LayoutColumn::Selector(col) => (format!("s{col}"), None),
+            };
+            let label = match annotation.and_then(|(annotation, _)| annotation.as_ref()) {
+                Some(annotation) => format!("{label} {annotation}"),
+                None => label,
+            };
+            let pixel_x = (column_width * (x as f64 + 0.5)) as i32;
+            header.draw_text(&label, &label_style, (pixel_x, HEADER_HEIGHT as i32 - 4))?;
+        }
+
+        Ok(())
+    }
+
+    // Renders the layout to an SVG file, sized to the table up to a limit
+    pub fn render_layout_svg(&self, cs: &ConstraintSystem<TermField>, k: u32, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let columns = cs.num_instance_columns() + cs.num_advice_columns() + cs.num_fixed_columns() + cs.num_selectors();
+        let width = (columns as u32 * 24).clamp(400, 4000);
+        let height = HEADER_HEIGHT + 1u32.checked_shl(k).unwrap_or(u32::MAX).saturating_mul(12).clamp(200, 4000);
+        let root = SVGBackend::new(path, (width, height)).into_drawing_area();
+        self.render_layout(cs, k, &root)?;
+        root.present()?;
+        Ok(())
+    }
+}
+
+fn truncate(text: &str) -> String {
+    if text.chars().count() <= MAX_TEXT_LEN {
+        text.to_string()
+    } else {
+        format!("{}…", text.chars().take(MAX_TEXT_LEN - 1).collect::<String>())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use halo2_proofs::circuit::{Layouter, SimpleFloorPlanner, Value};
+    use halo2_proofs::plonk::{Advice, Circuit, Column, Fixed, Selector};
+
+    use super::*;
+    use crate::extraction::ExtractionOptions;
+
+    const K: u32 = 4;
+
+    // a0 is copied to b1, next to a fixed cell and an enabled selector
+    struct CopyCircuit;
+
+    impl Circuit<TermField> for CopyCircuit {
+        type Config = (Column<Advice>, Column<Advice>, Column<Fixed>, Selector);
+        type FloorPlanner = SimpleFloorPlanner;
+        type Params = ();
+
+        fn without_witnesses(&self) -> Self {
+            Self
+        }
+
+        fn configure(meta: &mut ConstraintSystem<TermField>) -> Self::Config {
+            let (a, b) = (meta.advice_column(), meta.advice_column());
+            meta.enable_equality(a);
+            meta.enable_equality(b);
+            (a, b, meta.fixed_column(), meta.selector())
+        }
+
+        fn synthesize(&self, (a, b, f, s): Self::Config, mut layouter: impl Layouter<TermField>) -> Result<(), halo2_frontend::plonk::Error> {
+            layouter.assign_region(
+                || "copy",
+                |mut region| {
+                    region.name_column(|| "left", a);
+                    region.name_column(|| "right", b);
+                    let cell = region.assign_advice(|| "x", a, 0, || Value::known(TermField::zero()))?;
+                    cell.copy_advice(|| "y", &mut region, b, 1)?;
+                    region.assign_fixed(|| "f", f, 0, || Value::known(TermField::zero()))?;
+                    s.enable(&mut region, 0)?;
+                    Ok(())
+                },
+            )
+        }
+    }
+
+    // The svg tags of a kind whose attributes contain all of the given ones
+    fn count_tags(svg: &str, tag: &str, attributes: &[&str]) -> usize {
+        svg.split(&format!("<{tag} "))
+            .skip(1)
+            .filter(|rest| {
+                let attrs = rest.split('>').next().unwrap();
+                attributes.iter().all(|attribute| attrs.contains(attribute))
+            })
+            .count()
+    }
+
+    #[test]
+    fn test_render_layout() {
+        let (cs, extraction) = ExtractingAssignment::synthesize_circuit(&CopyCircuit, &ExtractionOptions::default()).unwrap();
+        let mut svg = String::new();
+        {
+            let root = SVGBackend::with_string(&mut svg, (400, 400)).into_drawing_area();
+            extraction.render_layout(&cs, K, &root).unwrap();
+            root.present().unwrap();
+        }
+
+        for label in ["a0 left", "a1 right", "f0", "s0"] {
+            assert!(svg.contains(&format!(">{label}<")), "missing column header {label}");
+        }
+        // The two assigned advice cells, the fixed cell and the enabled selector
+        assert_eq!(count_tags(&svg, "rect", &["fill=\"#FF0000\"", "opacity=\"0.5\""]), 2);
+        assert_eq!(count_tags(&svg, "rect", &["fill=\"#0000FF\"", "opacity=\"0.4\""]), 1);
+        assert_eq!(count_tags(&svg, "rect", &["fill=\"#FF00FF\"", "opacity=\"0.6\""]), 1);
+        // One copy arc
+        assert_eq!(count_tags(&svg, "polyline", &["stroke=\"#00FF00\""]), 1);
+    }
+}
@@ -1,4 +1,13 @@
+pub mod analysis;
+pub mod cse;
+pub mod diff;
+pub mod differential;
+pub mod evaluate;
 pub mod extraction;
 pub mod field;
+pub mod layout;
+pub mod printer;
 pub mod scroll;
+pub mod simplify;
+pub mod spec;
 pub mod utils;
\ No newline at end of file
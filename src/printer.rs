@@ -0,0 +1,243 @@
+use std::fmt::Display;
+
+use itertools::Itertools;
+
+// Lean 4 precedences of the operators we emit
+// + and - are infixl:65, * is infixl:70 and prefix - is 75
+pub const PREC_LOWEST: u32 = 0;
+pub const PREC_SUM: u32 = 65;
+pub const PREC_PRODUCT: u32 = 70;
+pub const PREC_NEG: u32 = 75;
+// Function application and atoms, which never need parentheses as an operand
+pub const PREC_MAX: u32 = 1024;
+
+pub const DEFAULT_LINE_WIDTH: usize = 100;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+}
+
+impl BinaryOp {
+    pub fn precedence(&self) -> u32 {
+        match self {
+            BinaryOp::Add | BinaryOp::Sub => PREC_SUM,
+            BinaryOp::Mul => PREC_PRODUCT,
+        }
+    }
+
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            BinaryOp::Add => "+",
+            BinaryOp::Sub => "-",
+            BinaryOp::Mul => "*",
+        }
+    }
+}
+
+// A Lean term built from already printed leaves, so that parentheses are only inserted where the grammar needs them
+#[derive(Clone, Debug)]
+pub enum LeanTerm {
+    // Verbatim Lean text, along with the precedence of its outermost operator
+    Text(String, u32),
+    Neg(Box<LeanTerm>),
+    Binary(BinaryOp, Box<LeanTerm>, Box<LeanTerm>),
+}
+
+impl LeanTerm {
+    pub fn text(text: impl Into<String>) -> Self {
+        let text = text.into();
+        let precedence = text_precedence(&text);
+        LeanTerm::Text(text, precedence)
+    }
+
+    // For leaves that are known to be an atom or application, such as column queries
+    pub fn app(text: impl Into<String>) -> Self {
+        LeanTerm::Text(text.into(), PREC_MAX)
+    }
+
+    pub fn neg(term: LeanTerm) -> Self {
+        LeanTerm::Neg(Box::new(term))
+    }
+
+    pub fn binary(op: BinaryOp, lhs: LeanTerm, rhs: LeanTerm) -> Self {
+        LeanTerm::Binary(op, Box::new(lhs), Box::new(rhs))
+    }
+
+    pub fn precedence(&self) -> u32 {
+        match self {
+            LeanTerm::Text(_, precedence) => *precedence,
+            LeanTerm::Neg(_) => PREC_NEG,
+            LeanTerm::Binary(op, _, _) => op.precedence(),
+        }
+    }
+
+    // Prints the term on a single line, parenthesised if it binds more loosely than min_prec
+    fn flat_at(&self, min_prec: u32) -> String {
+        let body = match self {
+            LeanTerm::Text(text, _) => text.clone(),
+            LeanTerm::Neg(term) => format!("-{}", neg_operand(term.flat_at(PREC_MAX))),
+            LeanTerm::Binary(op, lhs, rhs) => format!(
+                "{} {} {}",
+                lhs.flat_at(op.precedence()),
+                op.symbol(),
+                // All our operators are left associative, so a right operand of equal precedence keeps its parentheses
+                rhs.flat_at(op.precedence() + 1)
+            ),
+        };
+        parenthesise(body, self.precedence() < min_prec)
+    }
+
+    // Prints the term starting at column indent, breaking sums and products that do not fit within width
+    // Continuation lines start with the operator and are aligned with the first operand of the chain
+    pub fn render(&self, indent: usize, width: usize) -> String {
+        self.render_at(PREC_LOWEST, indent, width)
+    }
+
+    fn render_at(&self, min_prec: u32, indent: usize, width: usize) -> String {
+        let flat = self.flat_at(min_prec);
+        if indent + flat.chars().count() <= width {
+            return flat;
+        }
+
+        let needs_parens = self.precedence() < min_prec;
+        let column = if needs_parens { indent + 1 } else { indent };
+        let body = match self {
+            LeanTerm::Text(text, _) => text.clone(),
+            LeanTerm::Neg(term) => format!("-{}", neg_operand(term.render_at(PREC_MAX, column + 1, width))),
+            LeanTerm::Binary(op, _, _) => {
+                let precedence = op.precedence();
+                let (first, rest) = self.chain();
+                let mut res = first.render_at(precedence, column, width);
+                for (op, operand) in rest {
+                    let symbol = op.symbol();
+                    let operand_column = column + symbol.chars().count() + 1;
+                    res.push_str(&format!(
+                        "\n{}{symbol} {}",
+                        " ".repeat(column),
+                        operand.render_at(precedence + 1, operand_column, width)
+                    ));
+                }
+                res
+            }
+        };
+        parenthesise(body, needs_parens)
+    }
+
+    // Flattens a left associative chain of operators of the same precedence, such as a + b - c
+    fn chain(&self) -> (&LeanTerm, Vec<(BinaryOp, &LeanTerm)>) {
+        match self {
+            LeanTerm::Binary(op, lhs, rhs) if lhs.precedence() == op.precedence() => {
+                let (first, mut rest) = lhs.chain();
+                rest.push((*op, rhs));
+                (first, rest)
+            }
+            LeanTerm::Binary(op, lhs, rhs) => (lhs, vec![(*op, rhs)]),
+            _ => (self, vec![]),
+        }
+    }
+}
+
+impl Display for LeanTerm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.flat_at(PREC_LOWEST))
+    }
+}
+
+// Prints a tuple of terms on one line if it fits, otherwise one element per line
+pub fn render_tuple(terms: &[LeanTerm], indent: usize, width: usize) -> String {
+    let flat = format!("({})", terms.iter().join(", "));
+    if indent + flat.chars().count() <= width {
+        return flat;
+    }
+    let body = terms
+        .iter()
+        .map(|term| term.render(indent + 1, width))
+        .join(&format!(",\n{}", " ".repeat(indent + 1)));
+    format!("({body})")
+}
+
+fn parenthesise(body: String, needs_parens: bool) -> String {
+    if needs_parens {
+        format!("({body})")
+    } else {
+        body
+    }
+}
+
+// Avoid emitting --, which Lean would read as the start of a comment
+fn neg_operand(operand: String) -> String {
+    if operand.starts_with('-') {
+        format!("({operand})")
+    } else {
+        operand
+    }
+}
+
+// Finds the precedence of the outermost operator of some already printed Lean text
+// Anything we do not recognise is given the lowest precedence, so that it is always parenthesised
+pub fn text_precedence(text: &str) -> u32 {
+    let text = text.trim();
+    if text.starts_with("if ") || text.starts_with("λ") || text.starts_with("fun ") {
+        return PREC_LOWEST;
+    }
+
+    let mut depth: usize = 0;
+    let mut precedence = PREC_MAX;
+    let mut previous = None;
+    let mut chars = text.chars().peekable();
+    while let Some(ch) = chars.next() {
+        match ch {
+            '(' | '[' | '{' | '⟨' => depth += 1,
+            ')' | ']' | '}' | '⟩' => depth = depth.saturating_sub(1),
+            _ if depth > 0 => {},
+            '+' | '-' | '*' | '/' | '%' if previous == Some(' ') && chars.peek() == Some(&' ') => {
+                let op_precedence = if ch == '+' || ch == '-' { PREC_SUM } else { PREC_PRODUCT };
+                precedence = precedence.min(op_precedence);
+            },
+            '=' | '<' | '>' | '≤' | '≥' | '≠' | '∧' | '∨' | '→' | '↔' | ',' | '∀' | '∃' | '^' => return PREC_LOWEST,
+            _ => {},
+        }
+        previous = Some(ch);
+    }
+
+    if precedence == PREC_MAX && text.starts_with('-') {
+        PREC_NEG
+    } else {
+        precedence
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_minimal_parentheses() {
+        let a = || LeanTerm::app("c.get_advice 0 row");
+        let b = || LeanTerm::app("c.get_advice 1 row");
+        let sum = LeanTerm::binary(BinaryOp::Add, a(), b());
+        let product = LeanTerm::binary(BinaryOp::Mul, sum.clone(), LeanTerm::neg(b()));
+        assert_eq!(product.to_string(), "(c.get_advice 0 row + c.get_advice 1 row) * -c.get_advice 1 row");
+
+        let right_nested = LeanTerm::binary(BinaryOp::Sub, a(), sum);
+        assert_eq!(right_nested.to_string(), "c.get_advice 0 row - (c.get_advice 0 row + c.get_advice 1 row)");
+
+        let double_neg = LeanTerm::neg(LeanTerm::text("-5"));
+        assert_eq!(double_neg.to_string(), "-(-5)");
+    }
+
+    #[test]
+    fn test_line_breaking() {
+        let sum = (0..10)
+            .map(|col| LeanTerm::app(format!("c.get_advice {col} row")))
+            .reduce(|acc, term| LeanTerm::binary(BinaryOp::Add, acc, term))
+            .unwrap();
+        let rendered = sum.render(4, 60);
+        assert!(rendered.lines().all(|line| line.chars().count() + 4 <= 60));
+        assert_eq!(rendered.lines().count(), 10);
+        assert!(rendered.lines().skip(1).all(|line| line.starts_with("    + c.get_advice")));
+    }
+}
@@ -0,0 +1,355 @@
+use std::collections::BTreeMap;
+
+use halo2_proofs::plonk::Expression;
+
+use crate::field::TermField;
+
+// Expanding products of sums can blow up, so give up on the zero check past this many monomials
+const MAX_MONOMIALS: usize = 4096;
+
+// Simplifies an expression using only identities that hold in every commutative ring,
+// so the result is equal to the input whatever the field modulus is
+// Constants are folded, sums and products are flattened and identity terms are removed
+pub fn simplify(expr: &Expression<TermField>) -> Expression<TermField> {
+    match expr {
+        Expression::Constant(_)
+        | Expression::Selector(_)
+        | Expression::Fixed(_)
+        | Expression::Advice(_)
+        | Expression::Instance(_)
+        | Expression::Challenge(_) => expr.clone(),
+        Expression::Negated(inner) => negate(simplify(inner)),
+        Expression::Sum(_, _) => {
+            let mut summands = vec![];
+            collect_summands(expr, &mut summands);
+            build_sum(summands)
+        },
+        Expression::Product(_, _) | Expression::Scaled(_, _) => {
+            let mut factors = vec![];
+            let mut scale = TermField::one();
+            collect_factors(expr, &mut factors, &mut scale);
+            build_product(factors, scale)
+        },
+    }
+}
+
+// Whether the expression is provably zero for every assignment, in every field
+// This fully expands the polynomial, so it catches cancellations such as a * b - b * a that simplify does not
+pub fn is_identically_zero(expr: &Expression<TermField>) -> bool {
+    match expr {
+        Expression::Constant(TermField::Val(0)) => true,
+        _ => expand(expr).is_some_and(|monomials| monomials.is_empty()),
+    }
+}
+
+fn is_zero(value: &TermField) -> bool {
+    matches!(value, TermField::Val(0))
+}
+
+fn is_one(value: &TermField) -> bool {
+    matches!(value, TermField::Val(1))
+}
+
+fn negate(expr: Expression<TermField>) -> Expression<TermField> {
+    match expr {
+        Expression::Constant(value) => Expression::Constant(-value),
+        Expression::Negated(inner) => *inner,
+        Expression::Scaled(inner, factor) => {
+            let factor = -factor;
+            if is_one(&factor) {
+                *inner
+            } else {
+                Expression::Scaled(inner, factor)
+            }
+        },
+        expr => Expression::Negated(Box::new(expr)),
+    }
+}
+
+// Walks the sums of an unsimplified expression, simplifying each summand
+fn collect_summands(expr: &Expression<TermField>, summands: &mut Vec<Expression<TermField>>) {
+    match expr {
+        Expression::Sum(lhs, rhs) => {
+            collect_summands(lhs, summands);
+            collect_summands(rhs, summands);
+        },
+        _ => split_summands(simplify(expr), summands),
+    }
+}
+
+// Walks the sums of an already simplified expression
+fn split_summands(expr: Expression<TermField>, summands: &mut Vec<Expression<TermField>>) {
+    match expr {
+        Expression::Sum(lhs, rhs) => {
+            split_summands(*lhs, summands);
+            split_summands(*rhs, summands);
+        },
+        expr => summands.push(expr),
+    }
+}
+
+fn build_sum(summands: Vec<Expression<TermField>>) -> Expression<TermField> {
+    let mut constant = TermField::zero();
+    let mut terms = vec![];
+    for summand in summands {
+        match summand {
+            Expression::Constant(value) => constant += value,
+            summand => terms.push(summand),
+        }
+    }
+    if !is_zero(&constant) || terms.is_empty() {
+        terms.push(Expression::Constant(constant));
+    }
+    terms
+        .into_iter()
+        .reduce(|acc, term| Expression::Sum(Box::new(acc), Box::new(term)))
+        .expect("terms is not empty")
+}
+
+// Walks the products of an unsimplified expression, simplifying each factor and accumulating constants into scale
+fn collect_factors(expr: &Expression<TermField>, factors: &mut Vec<Expression<TermField>>, scale: &mut TermField) {
+    match expr {
+        Expression::Product(lhs, rhs) => {
+            collect_factors(lhs, factors, scale);
+            collect_factors(rhs, factors, scale);
+        },
+        Expression::Scaled(inner, factor) => {
+            *scale *= *factor;
+            collect_factors(inner, factors, scale);
+        },
+        _ => split_factors(simplify(expr), factors, scale),
+    }
+}
+
+// Walks the products of an already simplified expression
+fn split_factors(expr: Expression<TermField>, factors: &mut Vec<Expression<TermField>>, scale: &mut TermField) {
+    match expr {
+        Expression::Product(lhs, rhs) => {
+            split_factors(*lhs, factors, scale);
+            split_factors(*rhs, factors, scale);
+        },
+        Expression::Scaled(inner, factor) => {
+            *scale *= factor;
+            split_factors(*inner, factors, scale);
+        },
+        Expression::Negated(inner) => {
+            *scale = -*scale;
+            split_factors(*inner, factors, scale);
+        },
+        Expression::Constant(value) => *scale *= value,
+        expr => factors.push(expr),
+    }
+}
+
+fn build_product(factors: Vec<Expression<TermField>>, scale: TermField) -> Expression<TermField> {
+    if is_zero(&scale) {
+        return Expression::Constant(TermField::zero());
+    }
+    let Some(product) = factors
+        .into_iter()
+        .reduce(|acc, factor| Expression::Product(Box::new(acc), Box::new(factor)))
+    else {
+        return Expression::Constant(scale);
+    };
+    match scale {
+        TermField::Val(1) => product,
+        TermField::Val(-1) => Expression::Negated(Box::new(product)),
+        scale => Expression::Scaled(Box::new(product), scale),
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Variable {
+    Selector(usize),
+    Fixed(usize, i32),
+    Advice(usize, i32),
+    Instance(usize, i32),
+    Challenge(usize),
+}
+
+// A product of variables, kept sorted so that equal monomials compare equal
+type Monomial = Vec<Variable>;
+
+// Expands the expression into monomials with integer coefficients, omitting zero coefficients
+// Returns None if a constant is symbolic, a coefficient overflows or there are too many monomials
+fn expand(expr: &Expression<TermField>) -> Option<BTreeMap<Monomial, i128>> {
+    let variable = |variable| Some(BTreeMap::from([(vec![variable], 1)]));
+    match expr {
+        Expression::Constant(value) => constant_monomials(value),
+        Expression::Selector(selector) => variable(Variable::Selector(selector.index())),
+        Expression::Fixed(query) => variable(Variable::Fixed(query.column_index(), query.rotation().0)),
+        Expression::Advice(query) => variable(Variable::Advice(query.column_index(), query.rotation().0)),
+        Expression::Instance(query) => variable(Variable::Instance(query.column_index(), query.rotation().0)),
+        Expression::Challenge(challenge) => variable(Variable::Challenge(challenge.index())),
+        Expression::Negated(inner) => {
+            let mut monomials = expand(inner)?;
+            for coefficient in monomials.values_mut() {
+                *coefficient = coefficient.checked_neg()?;
+            }
+            Some(monomials)
+        },
+        Expression::Sum(lhs, rhs) => {
+            let mut monomials = expand(lhs)?;
+            for (monomial, coefficient) in expand(rhs)? {
+                add_monomial(&mut monomials, monomial, coefficient)?;
+            }
+            (monomials.len() <= MAX_MONOMIALS).then_some(monomials)
+        },
+        Expression::Product(lhs, rhs) => {
+            let lhs = expand(lhs)?;
+            let rhs = expand(rhs)?;
+            if lhs.len() * rhs.len() > MAX_MONOMIALS {
+                return None;
+            }
+            let mut monomials = BTreeMap::new();
+            for (lhs_monomial, lhs_coefficient) in &lhs {
+                for (rhs_monomial, rhs_coefficient) in &rhs {
+                    let mut monomial = lhs_monomial.iter().chain(rhs_monomial).cloned().collect::<Monomial>();
+                    monomial.sort();
+                    add_monomial(&mut monomials, monomial, lhs_coefficient.checked_mul(*rhs_coefficient)?)?;
+                }
+            }
+            Some(monomials)
+        },
+        Expression::Scaled(inner, factor) => {
+            let factor = constant_value(factor)?;
+            let mut monomials = BTreeMap::new();
+            for (monomial, coefficient) in expand(inner)? {
+                add_monomial(&mut monomials, monomial, coefficient.checked_mul(factor)?)?;
+            }
+            Some(monomials)
+        },
+    }
+}
+
+fn constant_value(value: &TermField) -> Option<i128> {
+    match value {
+        TermField::Val(x) => Some(*x as i128),
+        _ => None,
+    }
+}
+
+fn constant_monomials(value: &TermField) -> Option<BTreeMap<Monomial, i128>> {
+    let value = constant_value(value)?;
+    if value == 0 {
+        Some(BTreeMap::new())
+    } else {
+        Some(BTreeMap::from([(vec![], value)]))
+    }
+}
+
+fn add_monomial(monomials: &mut BTreeMap<Monomial, i128>, monomial: Monomial, coefficient: i128) -> Option<()> {
+    let sum = monomials.get(&monomial).unwrap_or(&0).checked_add(coefficient)?;
+    if sum == 0 {
+        monomials.remove(&monomial);
+    } else {
+        monomials.insert(monomial, sum);
+    }
+    Some(())
+}
+
+#[cfg(test)]
+mod test {
+    use halo2_proofs::plonk::ConstraintSystem;
+    use halo2_proofs::poly::Rotation;
+
+    use super::*;
+    use crate::extraction::expression_to_value_string;
+
+    // Queries of n advice columns and of a selector at the current row
+    fn queries(n: usize) -> (Vec<Expression<TermField>>, Expression<TermField>) {
+        let mut cs = ConstraintSystem::<TermField>::default();
+        let columns = (0..n).map(|_| cs.advice_column()).collect::<Vec<_>>();
+        let selector = cs.selector();
+        let mut queries = None;
+        cs.create_gate("queries", |meta| {
+            let advice = columns.iter().map(|column| meta.query_advice(*column, Rotation::cur())).collect();
+            queries = Some((advice, meta.query_selector(selector)));
+            vec![Expression::Constant(TermField::zero())]
+        });
+        queries.unwrap()
+    }
+
+    fn constant(value: u64) -> Expression<TermField> {
+        Expression::Constant(TermField::from(value))
+    }
+
+    fn show(expr: &Expression<TermField>) -> String {
+        expression_to_value_string(expr, "row")
+    }
+
+    #[test]
+    fn test_constant_folding() {
+        let (advice, _) = queries(1);
+        let a = advice[0].clone();
+        assert_eq!(show(&simplify(&((constant(2) + constant(3)) * a.clone()))), "5 * c.get_advice 0 row");
+        assert_eq!(show(&simplify(&(a.clone() * constant(0) + constant(4)))), "4");
+        assert_eq!(show(&simplify(&(a.clone() + constant(2) - constant(2)))), "c.get_advice 0 row");
+    }
+
+    #[test]
+    fn test_flattening() {
+        let (advice, s) = queries(3);
+        let (a, b, c) = (advice[0].clone(), advice[1].clone(), advice[2].clone());
+        let sum = a.clone() + (b.clone() + (constant(0) + c.clone()));
+        assert_eq!(show(&simplify(&sum)), "c.get_advice 0 row + c.get_advice 1 row + c.get_advice 2 row");
+        let product = (a.clone() + b.clone()) * (constant(1) * (s * (constant(2) * c)));
+        assert_eq!(
+            show(&simplify(&product)),
+            "2 * ((c.get_advice 0 row + c.get_advice 1 row) * c.get_selector 0 row * c.get_advice 2 row)"
+        );
+    }
+
+    #[test]
+    fn test_negate() {
+        let (advice, _) = queries(1);
+        let a = advice[0].clone();
+        assert_eq!(show(&negate(Expression::Scaled(Box::new(a.clone()), -TermField::one()))), "c.get_advice 0 row");
+        assert!(matches!(negate(Expression::Scaled(Box::new(a.clone()), TermField::from(2u64))), Expression::Scaled(_, TermField::Val(-2))));
+        assert_eq!(show(&negate(-a.clone())), "c.get_advice 0 row");
+        assert!(matches!(negate(constant(3)), Expression::Constant(TermField::Val(-3))));
+    }
+
+    #[test]
+    fn test_identically_zero() {
+        let (advice, _) = queries(2);
+        let (a, b) = (advice[0].clone(), advice[1].clone());
+        assert!(is_identically_zero(&(a.clone() * b.clone() - b.clone() * a.clone())));
+        assert!(is_identically_zero(&((a.clone() + b.clone()) * constant(2) - a.clone() * constant(2) - b.clone() - b.clone())));
+        assert!(!is_identically_zero(&(a.clone() * b.clone() - a.clone())));
+        assert!(!is_identically_zero(&(a.clone() * b.clone())));
+    }
+
+    #[test]
+    fn test_symbolic_constant() {
+        let (advice, _) = queries(1);
+        let a = advice[0].clone();
+        let symbol = Expression::Constant(TermField::from("c.1.sym_x"));
+        assert!(expand(&(a.clone() * symbol.clone())).is_none());
+        assert!(expand(&Expression::Scaled(Box::new(a.clone()), TermField::Delta)).is_none());
+        // Zero in every field, but the coefficient cannot be compared without knowing the symbol
+        assert!(!is_identically_zero(&(a.clone() * symbol.clone() - a * symbol)));
+    }
+
+    #[test]
+    fn test_max_monomials() {
+        // The product of n sums of two distinct variables has 2^n monomials
+        let product = |n: usize| {
+            let (advice, _) = queries(2 * n);
+            advice
+                .chunks(2)
+                .map(|pair| pair[0].clone() + pair[1].clone())
+                .reduce(|acc, sum| acc * sum)
+                .unwrap()
+        };
+        let small = product(6);
+        assert_eq!(expand(&small).map(|monomials| monomials.len()), Some(64));
+        assert!(is_identically_zero(&(small.clone() - small)));
+
+        // 2^13 monomials is past MAX_MONOMIALS
+        let large = product(13);
+        assert!(expand(&large).is_none());
+        // Past the cutoff the check gives up, so the gate is kept rather than dropped
+        assert!(!is_identically_zero(&(large.clone() - large)));
+    }
+}
@@ -0,0 +1,203 @@
+use std::collections::HashMap;
+use std::fs;
+
+use halo2_proofs::plonk::ConstraintSystem;
+use itertools::Itertools;
+
+use crate::extraction::{meets_constraints_conjuncts, shuffle_name, ExtractingAssignment, GROUPING_SIZE};
+use crate::field::TermField;
+use crate::utils::{group_props, GroupedDef};
+
+// Where to write the proof skeleton, and the module of the extracted model it imports
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SpecOptions {
+    pub path: String,
+    pub import: String,
+}
+
+// The projection of h picking out one conjunct of a right nested conjunction of len props
+fn conjunct(h: &str, position: usize, len: usize) -> String {
+    if position + 1 == len {
+        format!("{h}{}", ".2".repeat(position))
+    } else {
+        format!("{h}{}.1", ".2".repeat(position))
+    }
+}
+
+impl ExtractingAssignment<TermField> {
+    // A companion file with a theorem to prove about the circuit, and lemmas such as meets_constraints.gate_3
+    // that pick the individual constraints out of meets_constraints, so they can be used as h.gate_3
+    pub fn spec_skeleton(&self, cs: &ConstraintSystem<TermField>, namespace: &str, import: &str) -> String {
+        let num_gates = self.emitted_gates(cs).iter().filter(|gate| gate.trivial.is_none()).count();
+        // (final name, grouped defs, conjuncts of the final def)
+        let families = [
+            ("all_gates", group_props("gate_", num_gates, GROUPING_SIZE)),
+            ("all_copy_constraints", group_props("copy_", self.copies.len(), GROUPING_SIZE)),
+            ("all_lookups", group_props("lookup_", cs.lookups().len(), GROUPING_SIZE)),
+            ("all_shuffles", (vec![], cs.shuffles().iter().map(|shuffle| shuffle_name(shuffle.name())).collect_vec())),
+        ];
+
+        let field_mode = self.options.field_mode;
+        let circuit = field_mode.circuit_type();
+        let mut lines = vec![
+            format!("import {import}"),
+            String::new(),
+            format!("namespace {namespace}"),
+            String::new(),
+            format!("variable {}", field_mode.variables()),
+            String::new(),
+        ];
+        let meets_constraints = meets_constraints_conjuncts(cs, self.usable_rows(), &self.options);
+        let mut lemma = |child: &str, parent: &str, projection: String| {
+            lines.push(format!("theorem {child}_of_{parent} (c: {circuit}) (h: {parent} c) : {child} c := by"));
+            lines.push(format!("  unfold {parent} at h"));
+            lines.push(format!("  exact {projection}"));
+        };
+
+        let mut parents: HashMap<String, String> = HashMap::new();
+        let mut leaves = vec![];
+        for (final_name, (defs, final_body)) in &families {
+            if final_body.is_empty() {
+                continue;
+            }
+            let position = meets_constraints
+                .iter()
+                .position(|conjunct| *conjunct == format!("{final_name} c"))
+                .expect("Every family is a conjunct of meets_constraints");
+            lemma(final_name, "meets_constraints", conjunct("h", position, meets_constraints.len()));
+
+            for (name, def) in defs {
+                match def {
+                    GroupedDef::Prop(_) => leaves.push(name.clone()),
+                    GroupedDef::Group(body) => {
+                        for (position, child) in body.iter().enumerate() {
+                            lemma(child, name, conjunct("h", position, body.len()));
+                            parents.insert(child.clone(), name.clone());
+                        }
+                    },
+                }
+            }
+            for (position, child) in final_body.iter().enumerate() {
+                lemma(child, final_name, conjunct("h", position, final_body.len()));
+                parents.insert(child.clone(), final_name.to_string());
+            }
+            if defs.is_empty() {
+                leaves.extend(final_body.iter().cloned());
+            }
+            parents.insert(final_name.to_string(), "meets_constraints".to_string());
+        }
+
+        lines.push(String::new());
+        lines.push("-- Each constraint as a named hypothesis, e.g. h.gate_3 for h: meets_constraints c".to_string());
+        for leaf in &leaves {
+            let mut proof = "h".to_string();
+            let mut chain = vec![leaf.clone()];
+            while let Some(parent) = parents.get(chain.last().expect("chain is not empty")) {
+                chain.push(parent.clone());
+            }
+            for (child, parent) in chain.iter().rev().tuple_windows().map(|(parent, child)| (child, parent)) {
+                proof = format!("{child}_of_{parent} c ({proof})");
+            }
+            lines.push(format!("theorem meets_constraints.{leaf} (c: {circuit}) (h: meets_constraints c) : {leaf} c :="));
+            lines.push(format!("  {proof}"));
+        }
+
+        lines.push(String::new());
+        lines.push("-- The property the circuit is meant to have, to be filled in".to_string());
+        lines.push(format!("def Spec (c: {circuit}) : Prop := True"));
+        lines.push(format!("theorem spec (c: {circuit}) (h: meets_constraints c) : Spec c := by"));
+        lines.push("  sorry".to_string());
+        lines.push(String::new());
+        lines.push(format!("end {namespace}"));
+        lines.join("\n") + "\n"
+    }
+
+    pub fn write_spec(&self, cs: &ConstraintSystem<TermField>, namespace: &str, options: &SpecOptions) {
+        fs::write(&options.path, self.spec_skeleton(cs, namespace, &options.import)).expect("Failed to write spec file");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use halo2_proofs::circuit::{Layouter, SimpleFloorPlanner, Value};
+    use halo2_proofs::plonk::{Advice, Circuit, Column, Selector};
+    use halo2_proofs::poly::Rotation;
+
+    use super::*;
+    use crate::extraction::ExtractionOptions;
+
+    // Three gate polynomials, a copy, a lookup and a shuffle, so that every family but the gates has a single member
+    struct SpecCircuit;
+
+    impl Circuit<TermField> for SpecCircuit {
+        type Config = (Column<Advice>, Column<Advice>, Selector);
+        type FloorPlanner = SimpleFloorPlanner;
+        type Params = ();
+
+        fn without_witnesses(&self) -> Self {
+            Self
+        }
+
+        fn configure(meta: &mut ConstraintSystem<TermField>) -> Self::Config {
+            let (a, b) = (meta.advice_column(), meta.advice_column());
+            let s = meta.complex_selector();
+            meta.enable_equality(a);
+            meta.enable_equality(b);
+            meta.create_gate("g", |meta| {
+                let s = meta.query_selector(s);
+                let (a, b) = (meta.query_advice(a, Rotation::cur()), meta.query_advice(b, Rotation::cur()));
+                vec![s.clone() * a.clone(), s.clone() * b.clone(), s * (a - b)]
+            });
+            meta.lookup_any("l", |meta| vec![(meta.query_selector(s) * meta.query_advice(a, Rotation::cur()), meta.query_advice(b, Rotation::cur()))]);
+            meta.shuffle("perm", |meta| vec![(meta.query_advice(a, Rotation::cur()), meta.query_advice(b, Rotation::cur()))]);
+            (a, b, s)
+        }
+
+        fn synthesize(&self, (a, b, s): Self::Config, mut layouter: impl Layouter<TermField>) -> Result<(), halo2_frontend::plonk::Error> {
+            layouter.assign_region(
+                || "spec",
+                |mut region| {
+                    s.enable(&mut region, 0)?;
+                    let cell = region.assign_advice(|| "a", a, 0, || Value::known(TermField::zero()))?;
+                    cell.copy_advice(|| "b", &mut region, b, 0)?;
+                    Ok(())
+                },
+            )
+        }
+    }
+
+    #[test]
+    fn test_spec_skeleton() {
+        let (cs, extraction) = ExtractingAssignment::synthesize_circuit(&SpecCircuit, &ExtractionOptions::default()).unwrap();
+        let circuit = "(c: ValidCircuit P P_Prime)";
+        let lemma = |child: &str, parent: &str, projection: &str| {
+            format!("theorem {child}_of_{parent} {circuit} (h: {parent} c) : {child} c := by\n  unfold {parent} at h\n  exact {projection}")
+        };
+        let leaf = |leaf: &str, proof: &str| format!("theorem meets_constraints.{leaf} {circuit} (h: meets_constraints c) : {leaf} c :=\n  {proof}");
+        let expected = [
+            "import Spec.Model\n\nnamespace Spec\n\nvariable {P: ℕ} {P_Prime: Nat.Prime P}\n".to_string(),
+            // The families are the 7th to 10th of the 11 conjuncts of meets_constraints
+            lemma("all_gates", "meets_constraints", "h.2.2.2.2.2.2.1"),
+            lemma("gate_0", "all_gates", "h.1"),
+            lemma("gate_1", "all_gates", "h.2.1"),
+            lemma("gate_2", "all_gates", "h.2.2"),
+            lemma("all_copy_constraints", "meets_constraints", "h.2.2.2.2.2.2.2.1"),
+            // A family with a single member is that member
+            lemma("copy_0", "all_copy_constraints", "h"),
+            lemma("all_lookups", "meets_constraints", "h.2.2.2.2.2.2.2.2.1"),
+            lemma("lookup_0", "all_lookups", "h"),
+            lemma("all_shuffles", "meets_constraints", "h.2.2.2.2.2.2.2.2.2.1"),
+            lemma("shuffle_perm", "all_shuffles", "h"),
+            "\n-- Each constraint as a named hypothesis, e.g. h.gate_3 for h: meets_constraints c".to_string(),
+            leaf("gate_0", "gate_0_of_all_gates c (all_gates_of_meets_constraints c (h))"),
+            leaf("gate_1", "gate_1_of_all_gates c (all_gates_of_meets_constraints c (h))"),
+            leaf("gate_2", "gate_2_of_all_gates c (all_gates_of_meets_constraints c (h))"),
+            leaf("copy_0", "copy_0_of_all_copy_constraints c (all_copy_constraints_of_meets_constraints c (h))"),
+            leaf("lookup_0", "lookup_0_of_all_lookups c (all_lookups_of_meets_constraints c (h))"),
+            leaf("shuffle_perm", "shuffle_perm_of_all_shuffles c (all_shuffles_of_meets_constraints c (h))"),
+            format!("\n-- The property the circuit is meant to have, to be filled in\ndef Spec {circuit} : Prop := True"),
+            format!("theorem spec {circuit} (h: meets_constraints c) : Spec c := by\n  sorry\n\nend Spec\n"),
+        ];
+        assert_eq!(extraction.spec_skeleton(&cs, "Spec", "Spec.Model"), expected.join("\n"));
+    }
+}
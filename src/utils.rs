@@ -2,38 +2,64 @@ use std::collections::BTreeMap;
 
 use itertools::Itertools;
 
+// Consecutive rows that share an annotation, as (first row, last row, annotation)
 // start and end are inclusive
-pub fn get_group_annotations(annotations: &BTreeMap<usize, String>, start: usize, end: usize) -> Option<String> {
-    let mut grouped_comments = vec![];
-    for row in start..end+1 {
-        if let Some(annotation) = annotations.get(&row) {
-            if let Some ((_, last_end, last_comment)) = grouped_comments.last_mut() {
-                if row == *last_end + 1 && *last_comment == annotation {
-                    *last_end = row;
-                } else {
-                    grouped_comments.push((row, row, annotation));
-                }
-            } else {
-                grouped_comments.push((row, row, annotation));
+pub fn group_annotations(annotations: &BTreeMap<usize, String>, start: usize, end: usize) -> Vec<(usize, usize, &String)> {
+    let mut grouped_comments: Vec<(usize, usize, &String)> = vec![];
+    for (row, annotation) in annotations.range(start..=end) {
+        if let Some((_, last_end, last_comment)) = grouped_comments.last_mut() {
+            if *row == *last_end + 1 && *last_comment == annotation {
+                *last_end = *row;
+                continue;
             }
         }
+        grouped_comments.push((*row, *row, annotation));
+    }
+    grouped_comments
+}
+
+fn format_row_range(start: usize, end: usize) -> String {
+    if start == end {
+        start.to_string()
+    } else {
+        format!("{start}-{end}")
     }
+}
+
+// start and end are inclusive
+pub fn get_group_annotations(annotations: &BTreeMap<usize, String>, start: usize, end: usize) -> Option<String> {
+    let grouped_comments = group_annotations(annotations, start, end);
     if grouped_comments.is_empty() {
         None
     } else {
         Some(grouped_comments
             .iter()
-            .map(|(start, end, comment)| {
-                if *start == *end {
-                    format!("  -- {start}: {comment}")
-                } else {
-                    format!("  -- {start}-{end}: {comment}")
-                }
-            })
+            .map(|(start, end, comment)| format!("  -- {}: {comment}", format_row_range(*start, *end)))
             .join("\n"))
     }
 }
 
+// The row annotations as lines of a docstring, e.g. "rows 0-3: assign a"
+pub fn annotation_doc_lines(annotations: &BTreeMap<usize, String>) -> Vec<String> {
+    group_annotations(annotations, 0, usize::MAX)
+        .iter()
+        .map(|(start, end, comment)| {
+            let rows = if start == end { "row" } else { "rows" };
+            format!("{rows} {}: {comment}", format_row_range(*start, *end))
+        })
+        .collect_vec()
+}
+
+// A Lean docstring, which must not contain the closing -/
+pub fn make_lean_docstring(lines: &[String]) -> String {
+    let text = lines.join("\n").replace("-/", "- /");
+    if lines.len() <= 1 {
+        format!("/-- {text} -/")
+    } else {
+        format!("/--\n{text}\n-/")
+    }
+}
+
 pub fn group_values(column: &BTreeMap<usize, String>) -> Vec<(String, usize, Option<usize>)> {
     let mut res: Vec<(String, usize, Option<usize>)> = vec![];
 
@@ -52,6 +78,12 @@ pub fn group_values(column: &BTreeMap<usize, String>) -> Vec<(String, usize, Opt
     res
 }
 
+// Whether a rendered value is a numeric literal, which unlike c.1.sym_x or c.delta needs no circuit to be in scope
+pub fn is_numeral(value: &str) -> bool {
+    let digits = value.strip_prefix('-').unwrap_or(value);
+    !digits.is_empty() && digits.chars().all(|ch| ch.is_ascii_digit())
+}
+
 pub fn make_lean_comment(text: &str) -> String {
     text
         .split("\n")
@@ -59,14 +91,64 @@ pub fn make_lean_comment(text: &str) -> String {
         .join("\n")
 }
 
-pub fn print_grouped_props(prefix: &str, final_name: &str, props: &[String], group_size: usize) {
+// Mangles a name into a fragment of a Lean identifier, such as "fibonacci add" to fibonacci_add
+// ASCII letters and digits are kept and spaces become _, every other character is escaped with ',
+// as '_ for _ and 'hex' for the rest, so that distinct names never share a fragment
+// A leading digit is escaped too, so that gate_{fragment} cannot collide with the numbered gate_N
+pub fn mangle_identifier(name: &str) -> String {
+    let mut res = String::new();
+    for (idx, ch) in name.chars().enumerate() {
+        match ch {
+            '0'..='9' if idx == 0 => res.push_str(&format!("'{:x}'", ch as u32)),
+            ch if ch.is_ascii_alphanumeric() => res.push(ch),
+            ' ' => res.push('_'),
+            '_' => res.push_str("'_"),
+            ch => res.push_str(&format!("'{:x}'", ch as u32)),
+        }
+    }
+    res
+}
+
+// The name mangle_identifier produced a fragment from, or None if it is not a mangled name
+pub fn demangle_identifier(fragment: &str) -> Option<String> {
+    let mut res = String::new();
+    let mut chars = fragment.chars();
+    while let Some(ch) = chars.next() {
+        match ch {
+            '_' => res.push(' '),
+            '\'' => match chars.next()? {
+                '_' => res.push('_'),
+                first => {
+                    let hex = std::iter::once(first).chain(chars.by_ref().take_while(|ch| *ch != '\'')).collect::<String>();
+                    res.push(char::from_u32(u32::from_str_radix(&hex, 16).ok()?)?);
+                },
+            },
+            ch if ch.is_ascii_alphanumeric() => res.push(ch),
+            _ => return None,
+        }
+    }
+    // Rejects fragments mangle_identifier would not produce, such as an unescaped leading digit
+    Some(res).filter(|name| mangle_identifier(name) == fragment)
+}
+
+// A def emitted by print_grouped_props, in the order they are printed
+pub enum GroupedDef {
+    // One of the props, by index
+    Prop(usize),
+    // A conjunction of previously emitted defs
+    Group(Vec<String>),
+}
+
+// The defs print_grouped_props emits, and the defs the final def is a conjunction of
+// This is separate from the printing so that proofs can be generated against the same structure
+pub fn group_props(prefix: &str, count: usize, group_size: usize) -> (Vec<(String, GroupedDef)>, Vec<String>) {
     assert!(group_size > 1);
+    let mut defs = vec![];
     let mut groups = vec![vec![]];
 
-    for (idx, prop) in props.iter().enumerate() {
+    for idx in 0..count {
         let name = format!("{prefix}{idx}");
-        println!("def {name} (c: ValidCircuit P P_Prime) : Prop :=");
-        println!("  {prop}");
+        defs.push((name.clone(), GroupedDef::Prop(idx)));
         groups[0].push((idx, idx, name));
         let mut i = 0;
         while i < groups.len() {
@@ -76,10 +158,9 @@ pub fn print_grouped_props(prefix: &str, final_name: &str, props: &[String], gro
                 let name = format!("{prefix}{start}_to_{end}");
                 let body = groups[i]
                     .iter()
-                    .map(|(_, _, name)| format!("{name} c"))
-                    .join(" ∧ ");
-                println!("def {name} (c: ValidCircuit P P_Prime) : Prop :=");
-                println!("  {body}");
+                    .map(|(_, _, name)| name.clone())
+                    .collect_vec();
+                defs.push((name.clone(), GroupedDef::Group(body)));
                 if groups.len() == i+1 {
                     groups.push(vec![]);
                 }
@@ -94,16 +175,45 @@ pub fn print_grouped_props(prefix: &str, final_name: &str, props: &[String], gro
         .iter()
         .rev()
         .flatten()
-        .map(|(_, _, name)| format!("{name} c"))
-        .join(" ∧ ");
+        .map(|(_, _, name)| name.clone())
+        .collect_vec();
+
+    (defs, final_body)
+}
+
+pub fn print_grouped_props(prefix: &str, final_name: &str, circuit: &str, props: &[String], group_size: usize) {
+    let props = props.iter().map(|prop| (None, prop.clone())).collect_vec();
+    print_documented_grouped_props(prefix, final_name, circuit, &props, group_size);
+}
+
+// As print_grouped_props, with an optional docstring for each prop
+pub fn print_documented_grouped_props(prefix: &str, final_name: &str, circuit: &str, props: &[(Option<String>, String)], group_size: usize) {
+    let (defs, final_body) = group_props(prefix, props.len(), group_size);
 
-    let final_body = if final_body == "" {
-        "true"
+    for (name, def) in defs {
+        match def {
+            GroupedDef::Prop(idx) => {
+                let (doc, prop) = &props[idx];
+                if let Some(doc) = doc {
+                    println!("{}", make_lean_docstring(&[doc.clone()]));
+                }
+                println!("def {name} (c: {circuit}) : Prop :=");
+                println!("  {prop}");
+            },
+            GroupedDef::Group(body) => {
+                println!("def {name} (c: {circuit}) : Prop :=");
+                println!("  {}", body.iter().map(|name| format!("{name} c")).join(" ∧ "));
+            },
+        }
+    }
+
+    let final_body = if final_body.is_empty() {
+        "true".to_string()
     } else {
-        &final_body
+        final_body.iter().map(|name| format!("{name} c")).join(" ∧ ")
     };
 
-    println!("def {final_name} (c: ValidCircuit P P_Prime): Prop :=");
+    println!("def {final_name} (c: {circuit}): Prop :=");
     println!("  {final_body}");
 }
 
@@ -125,4 +235,53 @@ pub fn update_row_annotation(annotations: &mut BTreeMap<usize, (Option<String>,
         rows.insert(row, annotation);
         annotations.insert(col, (None, rows));
     }
-}
\ No newline at end of file
+}
+
+// A JSON string literal, for the hand written machine readable reports
+pub fn json_string(text: &str) -> String {
+    let mut res = String::from("\"");
+    for ch in text.chars() {
+        match ch {
+            '"' => res.push_str("\\\""),
+            '\\' => res.push_str("\\\\"),
+            '\n' => res.push_str("\\n"),
+            '\r' => res.push_str("\\r"),
+            '\t' => res.push_str("\\t"),
+            ch if (ch as u32) < 0x20 => res.push_str(&format!("\\u{:04x}", ch as u32)),
+            ch => res.push(ch),
+        }
+    }
+    res.push('"');
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mangled_identifiers_are_distinct_and_reversible() {
+        let names = ["fibonacci add", "fibonacci_add", "a_ b", "a _b", "a-b", "3 to 5", "x'y", "é", ""];
+        let mangled = names.iter().map(|name| mangle_identifier(name)).collect_vec();
+        assert_eq!(mangled[0], "fibonacci_add");
+        assert!(mangled.iter().all_unique());
+        for (name, fragment) in names.iter().zip(&mangled) {
+            assert!(fragment.chars().all(|ch| ch.is_ascii_alphanumeric() || ch == '_' || ch == '\''));
+            assert!(!fragment.starts_with(|ch: char| ch.is_ascii_digit()));
+            assert_eq!(demangle_identifier(fragment).as_deref(), Some(*name));
+        }
+        assert_eq!(demangle_identifier("3_to_5"), None);
+    }
+
+    #[test]
+    fn docstrings_cannot_be_closed_early() {
+        assert_eq!(make_lean_docstring(&["one line".to_string()]), "/-- one line -/");
+        assert_eq!(make_lean_docstring(&["a -/ b".to_string(), "c-/".to_string()]), "/--\na - / b\nc- /\n-/");
+    }
+
+    #[test]
+    fn annotation_doc_lines_group_consecutive_rows() {
+        let annotations = BTreeMap::from([(0, "a".to_string()), (1, "a".to_string()), (2, "b".to_string()), (4, "b".to_string())]);
+        assert_eq!(annotation_doc_lines(&annotations), vec!["rows 0-1: a", "row 2: b", "row 4: b"]);
+    }
+}